@@ -0,0 +1,102 @@
+// per-message latency of long-term memory, every message searches memory and adds an entry
+// before: the index is opened every turn and a new writer is created for every entry (0.1.x)
+// after: VectorDatabase keeps the index, reader and writer open for the whole session and commits in batches
+// run with `cargo bench --bench memory`
+#![allow(dead_code)]
+
+#[path = "../src/database.rs"]
+mod database;
+#[path = "../src/migrations.rs"]
+mod migrations;
+#[path = "../src/template.rs"]
+mod template;
+#[path = "../src/character.rs"]
+mod character;
+#[path = "../src/lorebook.rs"]
+mod lorebook;
+#[path = "../src/vectordb.rs"]
+mod vectordb;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tantivy::collector::TopDocs;
+use tantivy::query::QueryParser;
+use tantivy::schema::{SchemaBuilder, TEXT, STORED};
+use tantivy::Index;
+use database::MemorySettings;
+use vectordb::{VectorDatabase, MemoryEntry, MemoryFilter};
+
+const MESSAGES: usize = 200;
+const LONG_TERM_MEM: usize = 2;
+
+fn message(i: usize) -> String {
+    format!("{{{{user}}}}: tell me more about topic number {} and the weather on day {}\n{{{{char}}}}: topic {} is interesting, it was sunny on day {}\n", i % 13, i, i % 13, i)
+}
+
+fn baseline_message(path: &Path, text: &str) -> tantivy::Result<()> {
+    let mut schema_builder = SchemaBuilder::default();
+    let chat_field = schema_builder.add_text_field("chat", TEXT | STORED);
+    let schema = schema_builder.build();
+    let index = match Index::open_in_dir(path) {
+        Ok(index) => index,
+        Err(_) => Index::create_in_dir(path, schema)?,
+    };
+    let searcher = index.reader()?.searcher();
+    let query = QueryParser::for_index(&index, vec![chat_field]).parse_query("topic weather")?;
+    for (_, doc_address) in searcher.search(&query, &TopDocs::with_limit(LONG_TERM_MEM))? {
+        searcher.doc(doc_address)?;
+    }
+    let mut writer = index.writer(50_000_000)?;
+    writer.add_document(tantivy::doc!(chat_field => text))?;
+    writer.commit()?;
+    Ok(())
+}
+
+fn current_message(memory: &VectorDatabase, settings: &MemorySettings, text: &str) -> tantivy::Result<()> {
+    memory.get_matches("topic weather", None, LONG_TERM_MEM, settings, &MemoryFilter::default(), "relevance")?;
+    memory.add_entry(&MemoryEntry { text: text.to_string(), source: "conversation".to_string(), importance: 1.0, ..Default::default() }, None)?;
+    Ok(())
+}
+
+fn report(name: &str, mut latencies: Vec<Duration>) {
+    latencies.sort();
+    let total: Duration = latencies.iter().sum();
+    println!("{:<8} {} messages, mean {:>8.2?}, p50 {:>8.2?}, p95 {:>8.2?}, max {:>8.2?}",
+        name, latencies.len(), total / latencies.len() as u32, latencies[latencies.len() / 2], latencies[latencies.len() * 95 / 100], latencies[latencies.len() - 1]);
+}
+
+fn bench_dir(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("ai-companion-bench-{}-{}", name, std::process::id()));
+    if path.exists() {
+        fs::remove_dir_all(&path).unwrap();
+    }
+    fs::create_dir_all(&path).unwrap();
+    path
+}
+
+fn main() {
+    let before_path = bench_dir("before");
+    let mut before: Vec<Duration> = Vec::new();
+    for i in 0..MESSAGES {
+        let start = Instant::now();
+        baseline_message(&before_path, &message(i)).unwrap();
+        before.push(start.elapsed());
+    }
+    report("before", before);
+
+    let after_path = bench_dir("after");
+    let memory = VectorDatabase::connect(&after_path).unwrap();
+    let settings = MemorySettings::default();
+    let mut after: Vec<Duration> = Vec::new();
+    for i in 0..MESSAGES {
+        let start = Instant::now();
+        current_message(&memory, &settings, &message(i)).unwrap();
+        after.push(start.elapsed());
+    }
+    report("after", after);
+
+    drop(memory);
+    fs::remove_dir_all(&before_path).unwrap();
+    fs::remove_dir_all(&after_path).unwrap();
+}
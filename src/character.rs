@@ -0,0 +1,393 @@
+use serde::{Serialize, Deserialize, Deserializer};
+use base64::{Engine, engine::GeneralPurpose, engine::GeneralPurposeConfig, alphabet::STANDARD};
+use std::collections::HashMap;
+use crate::database::CompanionData;
+
+// fields of character cards, V1 cards have them at the top level,
+// chara_card_v2 and chara_card_v3 cards in the nested data object
+// https://github.com/malfoyslastname/character-card-spec-v2
+// https://github.com/kwaroran/character-card-spec-v3
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct CharacterCardData {
+    #[serde(deserialize_with = "null_as_default")]
+    pub name: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub description: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub personality: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub scenario: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub first_mes: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub mes_example: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub creator_notes: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub system_prompt: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub post_history_instructions: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub alternate_greetings: Vec<String>,
+    #[serde(deserialize_with = "null_as_default")]
+    pub tags: Vec<String>,
+    #[serde(deserialize_with = "null_as_default")]
+    pub creator: String,
+    #[serde(deserialize_with = "null_as_default")]
+    pub character_version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub character_book: Option<serde_json::Value>,
+    // data of other frontends, required by the spec even if empty
+    #[serde(deserialize_with = "null_as_default")]
+    pub extensions: serde_json::Map<String, serde_json::Value>,
+}
+
+// many cards have null instead of an empty string or list
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+pub fn parse_character_json(character_json_text: &str) -> Result<CharacterCardData, String> {
+    let value: serde_json::Value = match serde_json::from_str(character_json_text) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(format!("Error while parsing provided text as json: {:?}", e));
+        }
+    };
+    let data = match value.get("data") {
+        Some(data) if data.is_object() => data.clone(),
+        _ => value,
+    };
+    match serde_json::from_value(data) {
+        Ok(card) => Ok(card),
+        Err(e) => Err(format!("Error while reading character data from json: {:?}", e)),
+    }
+}
+
+// tEXt chunks can be before or after the image data, so every chunk is read
+fn png_text_chunks(png_bytes: &[u8]) -> Result<HashMap<String, String>, String> {
+    if png_bytes.len() < 8 || &png_bytes[..8] != b"\x89PNG\r\n\x1a\n" {
+        return Err("Character card is not a png image".to_string());
+    }
+    let mut text_chunks: HashMap<String, String> = HashMap::new();
+    let mut position = 8;
+    while position + 8 <= png_bytes.len() {
+        let length = u32::from_be_bytes([png_bytes[position], png_bytes[position + 1], png_bytes[position + 2], png_bytes[position + 3]]) as usize;
+        let chunk_type = &png_bytes[position + 4..position + 8];
+        let data_start = position + 8;
+        let data_end = data_start + length;
+        if data_end > png_bytes.len() {
+            return Err("Character card png image is truncated".to_string());
+        }
+        if chunk_type == b"tEXt" {
+            let data = &png_bytes[data_start..data_end];
+            if let Some(separator) = data.iter().position(|b| *b == 0) {
+                let keyword = String::from_utf8_lossy(&data[..separator]).to_string();
+                let text = String::from_utf8_lossy(&data[separator + 1..]).to_string();
+                text_chunks.insert(keyword, text);
+            }
+        }
+        if chunk_type == b"IEND" {
+            break;
+        }
+        position = data_end + 4; // crc
+    }
+    Ok(text_chunks)
+}
+
+// V3 cards have a ccv3 chunk, usually next to a chara chunk with V2 data for older frontends
+pub fn read_character_card(png_bytes: &[u8]) -> Result<CharacterCardData, String> {
+    let text_chunks = png_text_chunks(png_bytes)?;
+    let character_base64 = match text_chunks.get("ccv3").or_else(|| text_chunks.get("chara")) {
+        Some(text) => text,
+        None => {
+            return Err("No tEXt chunk with name 'ccv3' or 'chara' found".to_string());
+        }
+    };
+    let engine = GeneralPurpose::new(&STANDARD, GeneralPurposeConfig::new());
+    let character_bytes = match engine.decode(character_base64.trim()) {
+        Ok(b) => b,
+        Err(e) => {
+            return Err(format!("Error while decoding base64 character data from character card: {:?}", e));
+        }
+    };
+    let character_text: &str = match std::str::from_utf8(&character_bytes) {
+        Ok(s) => s,
+        Err(e) => {
+            return Err(format!("Error while parsing decoded base64 bytes to utf8 string: {:?}", e));
+        }
+    };
+    parse_character_json(character_text)
+}
+
+#[derive(Serialize)]
+struct CharacterCardV2<'a> {
+    spec: &'a str,
+    spec_version: &'a str,
+    data: &'a CharacterCardData,
+}
+
+pub fn card_from_companion(companion: &CompanionData) -> CharacterCardData {
+    CharacterCardData {
+        name: companion.name.clone(),
+        description: companion.persona.clone(),
+        personality: companion.personality.clone(),
+        scenario: companion.scenario.clone(),
+        first_mes: companion.first_message.clone(),
+        mes_example: companion.example_dialogue.clone(),
+        creator_notes: companion.creator_notes.clone(),
+        system_prompt: companion.system_prompt.clone(),
+        post_history_instructions: companion.post_history_instructions.clone(),
+        alternate_greetings: companion.alternate_greetings.clone(),
+        tags: companion.tags.clone(),
+        creator: String::new(),
+        character_version: String::new(),
+        // built from the lorebook by the caller
+        character_book: None,
+        extensions: serde_json::from_str(&companion.extensions).unwrap_or_default(),
+    }
+}
+
+// size of the generated avatar if none is given
+const PLACEHOLDER_SIZE: u32 = 256;
+
+fn placeholder_png() -> Result<Vec<u8>, String> {
+    let mut png_bytes: Vec<u8> = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut png_bytes, PLACEHOLDER_SIZE, PLACEHOLDER_SIZE);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let pixels: Vec<u8> = [0x5a, 0x6f, 0x8c].repeat((PLACEHOLDER_SIZE * PLACEHOLDER_SIZE) as usize);
+        let mut writer = match encoder.write_header() {
+            Ok(w) => w,
+            Err(e) => {
+                return Err(format!("Error while encoding placeholder avatar: {:?}", e));
+            }
+        };
+        if let Err(e) = writer.write_image_data(&pixels) {
+            return Err(format!("Error while encoding placeholder avatar: {:?}", e));
+        }
+    }
+    Ok(png_bytes)
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc: u32 = 0xffffffff;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// copies the chunks of the image without old character data and puts the new chara chunk before IEND
+fn with_chara_chunk(png_bytes: &[u8], character_base64: &str) -> Result<Vec<u8>, String> {
+    if png_bytes.len() < 8 || &png_bytes[..8] != b"\x89PNG\r\n\x1a\n" {
+        return Err("Avatar is not a png image".to_string());
+    }
+    let mut card: Vec<u8> = png_bytes[..8].to_vec();
+    let mut position = 8;
+    while position + 8 <= png_bytes.len() {
+        let length = u32::from_be_bytes([png_bytes[position], png_bytes[position + 1], png_bytes[position + 2], png_bytes[position + 3]]) as usize;
+        let chunk_type = &png_bytes[position + 4..position + 8];
+        let chunk_end = position + 12 + length;
+        if chunk_end > png_bytes.len() {
+            return Err("Avatar png image is truncated".to_string());
+        }
+        let data = &png_bytes[position + 8..position + 8 + length];
+        let is_character_data = chunk_type == b"tEXt" && (data.starts_with(b"chara\0") || data.starts_with(b"ccv3\0"));
+        if chunk_type == b"IEND" {
+            let mut chunk: Vec<u8> = b"tEXtchara\0".to_vec();
+            chunk.extend_from_slice(character_base64.as_bytes());
+            card.extend_from_slice(&((chunk.len() - 4) as u32).to_be_bytes());
+            card.extend_from_slice(&chunk);
+            card.extend_from_slice(&crc32(&chunk).to_be_bytes());
+            card.extend_from_slice(&png_bytes[position..chunk_end]);
+            return Ok(card);
+        }
+        if !is_character_data {
+            card.extend_from_slice(&png_bytes[position..chunk_end]);
+        }
+        position = chunk_end;
+    }
+    Err("Avatar png image has no IEND chunk".to_string())
+}
+
+// V2 card, the image is the avatar or a generated placeholder
+pub fn write_character_card(card: &CharacterCardData, avatar_png: Option<&[u8]>) -> Result<Vec<u8>, String> {
+    let card_v2 = CharacterCardV2 {
+        spec: "chara_card_v2",
+        spec_version: "2.0",
+        data: card,
+    };
+    let character_json = match serde_json::to_string(&card_v2) {
+        Ok(j) => j,
+        Err(e) => {
+            return Err(format!("Error while encoding companion data as json: {:?}", e));
+        }
+    };
+    let engine = GeneralPurpose::new(&STANDARD, GeneralPurposeConfig::new());
+    let character_base64 = engine.encode(character_json);
+    match avatar_png {
+        Some(png_bytes) => with_chara_chunk(png_bytes, &character_base64),
+        None => with_chara_chunk(&placeholder_png()?, &character_base64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card() -> CharacterCardData {
+        CharacterCardData {
+            name: "Aiko".to_string(),
+            description: "{{char}} is a librarian, she says \"shh\" a lot".to_string(),
+            personality: "calm; curious".to_string(),
+            scenario: "{{user}} visits the library".to_string(),
+            first_mes: "Welcome, {{user}}! 📚".to_string(),
+            mes_example: "<START>\n{{user}}: Hi\n{{char}}: Hello".to_string(),
+            creator_notes: "zażółć gęślą jaźń".to_string(),
+            system_prompt: "{{original}} Stay in character".to_string(),
+            post_history_instructions: "Keep replies short".to_string(),
+            alternate_greetings: vec!["Good morning".to_string(), "Back again, {{user}}?".to_string()],
+            tags: vec!["library".to_string(), "slice of life".to_string()],
+            creator: "someone".to_string(),
+            character_version: "1.2".to_string(),
+            character_book: Some(serde_json::json!({
+                "name": "Library",
+                "token_budget": 256,
+                "extensions": {},
+                "entries": [{
+                    "keys": ["archive"],
+                    "content": "The archive is in the basement",
+                    "insertion_order": 100,
+                    "enabled": true,
+                    "extensions": {},
+                }],
+            })),
+            extensions: serde_json::json!({
+                "depth_prompt": { "prompt": "{{char}} whispers", "depth": 4 },
+                "talkativeness": "0.5",
+            }).as_object().unwrap().clone(),
+        }
+    }
+
+    fn avatar() -> Vec<u8> {
+        let mut png_bytes: Vec<u8> = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut png_bytes, 2, 2);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&[255, 0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, 255, 255, 255, 0]).unwrap();
+        }
+        png_bytes
+    }
+
+    // puts a tEXt chunk right after the IHDR chunk
+    fn with_text_chunk(png_bytes: &[u8], keyword: &str, text: &str) -> Vec<u8> {
+        let ihdr_end = 8 + 12 + 13;
+        let mut chunk: Vec<u8> = b"tEXt".to_vec();
+        chunk.extend_from_slice(keyword.as_bytes());
+        chunk.push(0);
+        chunk.extend_from_slice(text.as_bytes());
+        let mut result = png_bytes[..ihdr_end].to_vec();
+        result.extend_from_slice(&((chunk.len() - 4) as u32).to_be_bytes());
+        result.extend_from_slice(&chunk);
+        result.extend_from_slice(&crc32(&chunk).to_be_bytes());
+        result.extend_from_slice(&png_bytes[ihdr_end..]);
+        result
+    }
+
+    fn text_chunk_count(png_bytes: &[u8], keyword: &str) -> usize {
+        let mut count = 0;
+        let mut position = 8;
+        while position + 8 <= png_bytes.len() {
+            let length = u32::from_be_bytes([png_bytes[position], png_bytes[position + 1], png_bytes[position + 2], png_bytes[position + 3]]) as usize;
+            let data = &png_bytes[position + 8..position + 8 + length];
+            if &png_bytes[position + 4..position + 8] == b"tEXt" && data.starts_with(format!("{}\0", keyword).as_bytes()) {
+                count += 1;
+            }
+            position += 12 + length;
+        }
+        count
+    }
+
+    fn pixels(png_bytes: &[u8]) -> Vec<u8> {
+        let mut reader = png::Decoder::new(png_bytes).read_info().unwrap();
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).unwrap();
+        buffer.truncate(info.buffer_size());
+        buffer
+    }
+
+    fn chara_json(png_bytes: &[u8]) -> serde_json::Value {
+        let engine = GeneralPurpose::new(&STANDARD, GeneralPurposeConfig::new());
+        let character_bytes = engine.decode(&png_text_chunks(png_bytes).unwrap()["chara"]).unwrap();
+        serde_json::from_slice(&character_bytes).unwrap()
+    }
+
+    fn encoded(card: &CharacterCardData) -> String {
+        let engine = GeneralPurpose::new(&STANDARD, GeneralPurposeConfig::new());
+        engine.encode(serde_json::to_string(card).unwrap())
+    }
+
+    #[test]
+    fn card_with_placeholder_avatar_round_trips() {
+        let card = card();
+        let png_bytes = write_character_card(&card, None).unwrap();
+        let imported = read_character_card(&png_bytes).unwrap();
+        assert_eq!(serde_json::to_value(&imported).unwrap(), serde_json::to_value(&card).unwrap());
+        assert_eq!(imported.alternate_greetings, card.alternate_greetings);
+        assert_eq!(imported.character_book, card.character_book);
+        assert_eq!(text_chunk_count(&png_bytes, "chara"), 1);
+    }
+
+    #[test]
+    fn card_has_every_field_of_the_spec() {
+        let empty_card = CharacterCardData { name: "Empty".to_string(), ..Default::default() };
+        let json = chara_json(&write_character_card(&empty_card, None).unwrap());
+        assert_eq!(json["spec"], "chara_card_v2");
+        assert_eq!(json["spec_version"], "2.0");
+        for key in ["name", "description", "personality", "scenario", "first_mes", "mes_example", "creator_notes", "system_prompt",
+                "post_history_instructions", "alternate_greetings", "tags", "creator", "character_version"] {
+            assert!(json["data"].get(key).is_some(), "{} is missing", key);
+        }
+        assert_eq!(json["data"]["extensions"], serde_json::json!({}));
+        // the lorebook is optional, but has to be an object if it is there
+        assert!(json["data"].get("character_book").is_none());
+        let json = chara_json(&write_character_card(&card(), None).unwrap());
+        assert_eq!(json["data"]["extensions"], serde_json::Value::Object(card().extensions));
+        assert!(json["data"]["character_book"].is_object());
+    }
+
+    #[test]
+    fn card_with_avatar_round_trips_and_keeps_the_image() {
+        let card = card();
+        let avatar = avatar();
+        let png_bytes = write_character_card(&card, Some(&avatar)).unwrap();
+        let imported = read_character_card(&png_bytes).unwrap();
+        assert_eq!(serde_json::to_value(&imported).unwrap(), serde_json::to_value(&card).unwrap());
+        assert_eq!(pixels(&png_bytes), pixels(&avatar));
+    }
+
+    #[test]
+    fn existing_character_data_in_avatar_is_replaced() {
+        let old_card = CharacterCardData { name: "Old".to_string(), ..Default::default() };
+        let avatar = with_text_chunk(&with_text_chunk(&avatar(), "chara", &encoded(&old_card)), "ccv3", &encoded(&old_card));
+        assert_eq!(read_character_card(&avatar).unwrap().name, "Old");
+        let card = card();
+        let png_bytes = write_character_card(&card, Some(&avatar)).unwrap();
+        assert_eq!(text_chunk_count(&png_bytes, "chara"), 1);
+        assert_eq!(text_chunk_count(&png_bytes, "ccv3"), 0);
+        assert_eq!(serde_json::to_value(read_character_card(&png_bytes).unwrap()).unwrap(), serde_json::to_value(&card).unwrap());
+        assert_eq!(pixels(&png_bytes), pixels(&avatar));
+    }
+}
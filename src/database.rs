@@ -1,10 +1,17 @@
 use pyo3::prelude::*;
-use rusqlite::{Connection, Result, Error};
+use rusqlite::{Connection, OptionalExtension, Result, Error};
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, Local};
+use std::path::Path;
+use std::time::Duration;
+use crate::template::PromptTemplate;
+use crate::migrations;
+use crate::character::CharacterCardData;
+use rand::Rng;
+use crate::lorebook::LorebookEntry;
 
 #[derive(Serialize, Deserialize)]
-#[pyclass]
+#[pyclass(get_all)]
 pub struct Message {
     pub id: u32,
     pub ai: String,
@@ -13,7 +20,7 @@ pub struct Message {
 }
 
 #[derive(Serialize, Deserialize, Default)]
-#[pyclass]
+#[pyclass(get_all)]
 pub struct CompanionData {
     pub id: u32,
     pub name: String,
@@ -24,69 +31,410 @@ pub struct CompanionData {
     pub short_term_mem: u32,
     pub roleplay: u32,
     pub avatar_path: String,
+    // fields from character cards, extensions are kept as json and the character book is the lorebook
+    pub personality: String,
+    pub scenario: String,
+    pub system_prompt: String,
+    pub post_history_instructions: String,
+    pub alternate_greetings: Vec<String>,
+    pub tags: Vec<String>,
+    pub creator_notes: String,
+    pub extensions: String,
+}
+
+// greeting that new and cleared chats start with, index 0 is the first message
+// and the next ones are the alternate greetings
+#[derive(Clone, Copy)]
+pub enum Greeting {
+    Index(usize),
+    Random,
 }
 
 #[derive(Serialize, Deserialize, Default)]
-#[pyclass]
+#[pyclass(get_all)]
+pub struct ChatData {
+    pub id: u32,
+    pub name: String,
+    pub created: String,
+    pub last_activity: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[pyclass(get_all)]
 pub struct UserData {
     pub id: u32,
     pub name: String,
     pub persona: String,
 }
 
-pub struct Database {}
+// index is the position of the swipe among the swipes of the message
+#[derive(Clone)]
+#[pyclass(get_all)]
+pub struct Swipe {
+    pub index: usize,
+    pub text: String,
+    pub created: String,
+    pub selected: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[pyclass(get_all, set_all)]
+pub struct SamplingSettings {
+    pub temperature: f32,
+    pub top_k: usize,
+    pub top_p: f32,
+    pub repeat_penalty: f32,
+    pub repetition_window: usize,
+    pub seed: Option<u64>,
+    pub max_new_tokens: Option<usize>,
+}
+
+impl Default for SamplingSettings {
+    fn default() -> Self {
+        // same values as llm's default sampler chain
+        SamplingSettings {
+            temperature: 0.8,
+            top_k: 40,
+            top_p: 0.95,
+            repeat_penalty: 1.3,
+            repetition_window: 64,
+            seed: None,
+            max_new_tokens: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[pyclass(get_all, set_all)]
+pub struct ContextSettings {
+    pub context_length: usize,
+    pub response_reserve: usize,
+    // tokens that triggered lorebook entries can take at most
+    pub lorebook_budget: usize,
+}
+
+impl Default for ContextSettings {
+    fn default() -> Self {
+        ContextSettings {
+            context_length: 2048,
+            response_reserve: 256,
+            lorebook_budget: 512,
+        }
+    }
+}
+
+#[derive(Clone)]
+#[pyclass(get_all)]
+pub struct Summary {
+    pub id: u32,
+    pub text: String,
+    pub first_message_id: u32,
+    pub last_message_id: u32,
+    pub created: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[pyclass(get_all)]
+pub struct MemorySettings {
+    pub retrieval: String,
+    pub fusion: String,
+    pub keyword_weight: f32,
+    pub vector_weight: f32,
+    // memories scoring below it (scores are between 0 and 1 and don't depend on the other results) are not recalled
+    pub min_score: f32,
+    // unsummarized messages outside of short-term memory needed to summarize them, 0 disables summaries
+    pub summarize_after: u32,
+}
+
+impl Default for MemorySettings {
+    fn default() -> Self {
+        MemorySettings {
+            retrieval: "bm25".to_string(),
+            fusion: "rrf".to_string(),
+            keyword_weight: 1.0,
+            vector_weight: 1.0,
+            min_score: 0.0,
+            summarize_after: 0,
+        }
+    }
+}
+
+// the connection is kept open for the whole lifetime of the companion
+pub struct Database {
+    con: Connection,
+    pub companion_id: u32,
+    pub user_id: u32,
+    pub chat_id: u32,
+}
+
+const DEFAULT_PERSONA: &str = "{{char}} is an artificial intelligence chatbot designed to help {{user}}. {{char}} is an artificial intelligence created in ai-companion backend";
+const DEFAULT_EXAMPLE_DIALOGUE: &str = "{{user}}: What is ai-companion?\n{{char}}: AI Companion is a project that aims to provide users with their own personal AI chatbot on their computer. It allows users to engage in friendly and natural conversations with their AI, creating a unique and personalized experience. This software can also be used as a backend or API for other projects that require a personalised AI chatbot.\n{{user}}: Can you tell me about the creator of ai-companion?\n{{char}}: the creator of the ai-companion program is 'Hubert Kasperek', he is a young programmer from Poland who is mostly interested in: web development (Backend), cybersecurity and computer science concepts";
 
 impl Database {
-    pub fn create() -> Result<usize> {
-        let con = Connection::open("companion.db")?;
+    pub fn new(path: &Path) -> Result<Database, Error> {
+        let con = Connection::open(path)?;
+        // WAL lets other processes read the database while a message is written
+        con.query_row("PRAGMA journal_mode=WAL", [], |row| row.get::<_, String>(0))?;
+        con.busy_timeout(Duration::from_secs(5))?;
+        Ok(Database {
+            con,
+            companion_id: 0,
+            user_id: 0,
+            chat_id: 0,
+        })
+    }
+
+    pub fn create(&mut self) -> Result<(), Error> {
+        migrations::migrate(&mut self.con)?;
+        if Database::is_table_empty("companion", &self.con) {
+            Database::insert_companion(&self.con, "Assistant", DEFAULT_PERSONA, DEFAULT_EXAMPLE_DIALOGUE, "Hello {{user}}, how can i help you?")?;
+        }
+        if Database::is_table_empty("user", &self.con) {
+            self.con.execute(
+                "INSERT INTO user (id, name, persona) VALUES (NULL, \"user\", \"{{user}} is chatting with {{char}} using ai-companion web user interface\")", []
+            )?;
+        }
+        Database::insert_missing_settings(&self.con)?;
+        self.companion_id = self.con.query_row("SELECT MIN(id) FROM companion", [], |row| row.get(0))?;
+        self.user_id = self.con.query_row("SELECT MIN(id) FROM user", [], |row| row.get(0))?;
+        self.select_latest_chat()
+    }
+
+    // sortable timestamp of chat creation and last activity
+    fn timestamp() -> String {
+        Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
+    }
+
+    fn insert_companion(con: &Connection, name: &str, persona: &str, example_dialogue: &str, first_message: &str) -> Result<u32, Error> {
         con.execute(
-            "CREATE TABLE IF NOT EXISTS messages (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                ai BOOLEAN NOT NULL,
-                text TEXT NOT NULL,
-                date TEXT NOT NULL
-            )", [],
+            "INSERT INTO companion (id, name, persona, example_dialogue, first_message, long_term_mem, short_term_mem, roleplay, avatar_path) VALUES (NULL, ?1, ?2, ?3, ?4, 2, 5, 1, \"/assets/companion_avatar-4rust.jpg\")",
+            [&name, &persona, &example_dialogue, &first_message]
         )?;
+        let companion_id = con.last_insert_rowid() as u32;
+        Database::insert_missing_settings(con)?;
+        Ok(companion_id)
+    }
+
+    // every companion has its own sampling settings, context settings, prompt template and memory settings
+    fn insert_missing_settings(con: &Connection) -> Result<(), Error> {
+        let default_sampling = SamplingSettings::default();
         con.execute(
-            "CREATE TABLE IF NOT EXISTS user (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                name TEXT NOT NULL,
-                persona TEXT NOT NULL
-            )", [],
+            "INSERT INTO sampling (id, temperature, top_k, top_p, repeat_penalty, repetition_window, seed, max_new_tokens, companion_id)
+                SELECT NULL, ?1, ?2, ?3, ?4, ?5, NULL, NULL, id FROM companion WHERE id NOT IN (SELECT companion_id FROM sampling)",
+            rusqlite::params![default_sampling.temperature, default_sampling.top_k, default_sampling.top_p, default_sampling.repeat_penalty, default_sampling.repetition_window]
         )?;
+        let default_context = ContextSettings::default();
         con.execute(
-            "CREATE TABLE IF NOT EXISTS companion (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                name TEXT NOT NULL,
-                persona TEXT NOT NULL,
-                example_dialogue TEXT NOT NULL,
-                first_message TEXT NOT NULL,
-                long_term_mem INTEGER NOT NULL,
-                short_term_mem INTEGER NOT NULL,
-                roleplay INTEGER NOT NULL,
-                avatar_path STRING NOT NULL
-            )", [],
+            "INSERT INTO context_settings (id, context_length, response_reserve, lorebook_budget, companion_id)
+                SELECT NULL, ?1, ?2, ?3, id FROM companion WHERE id NOT IN (SELECT companion_id FROM context_settings)",
+            rusqlite::params![default_context.context_length, default_context.response_reserve, default_context.lorebook_budget]
         )?;
-        if Database::is_table_empty("companion", &con) {
-            con.execute(
-                "INSERT INTO companion (id, name, persona, example_dialogue, first_message, long_term_mem, short_term_mem, roleplay, avatar_path) VALUES (NULL, \"Assistant\", \"{{char}} is an artificial intelligence chatbot designed to help {{user}}. {{char}} is an artificial intelligence created in ai-companion backend\", \"{{user}}: What is ai-companion?\n{{char}}: AI Companion is a project that aims to provide users with their own personal AI chatbot on their computer. It allows users to engage in friendly and natural conversations with their AI, creating a unique and personalized experience. This software can also be used as a backend or API for other projects that require a personalised AI chatbot.\n{{user}}: Can you tell me about the creator of ai-companion?\n{{char}}: the creator of the ai-companion program is 'Hubert Kasperek', he is a young programmer from Poland who is mostly interested in: web development (Backend), cybersecurity and computer science concepts\", \"Hello {{user}}, how can i help you?\", 2, 5, 1, \"/assets/companion_avatar-4rust.jpg\")", []
-            )?;
+        con.execute(
+            "INSERT INTO prompt_template (id, name, context, user_message, ai_message, stop_sequences, companion_id)
+                SELECT NULL, \"pygmalion\", \"\", \"\", \"\", \"[]\", id FROM companion WHERE id NOT IN (SELECT companion_id FROM prompt_template)", []
+        )?;
+        let default_memory = MemorySettings::default();
+        con.execute(
+            "INSERT INTO memory_settings (id, retrieval, fusion, keyword_weight, vector_weight, min_score, summarize_after, companion_id)
+                SELECT NULL, ?1, ?2, ?3, ?4, ?5, ?6, id FROM companion WHERE id NOT IN (SELECT companion_id FROM memory_settings)",
+            rusqlite::params![default_memory.retrieval, default_memory.fusion, default_memory.keyword_weight, default_memory.vector_weight, default_memory.min_score, default_memory.summarize_after]
+        )?;
+        Ok(())
+    }
+
+    // switches to the most recently used chat of the active companion and user, creating one if there are none
+    fn select_latest_chat(&mut self) -> Result<(), Error> {
+        let latest_chat: Option<u32> = self.con.query_row(
+            "SELECT id FROM chat WHERE companion_id=?1 AND user_id=?2 ORDER BY last_activity DESC, id DESC LIMIT 1",
+            [self.companion_id, self.user_id], |row| row.get(0)
+        ).optional()?;
+        match latest_chat {
+            Some(chat_id) => {
+                self.chat_id = chat_id;
+                Ok(())
+            },
+            None => {
+                self.create_chat("Chat", Greeting::Index(0))?;
+                Ok(())
+            }
         }
-        if Database::is_table_empty("user", &con) {
-            con.execute(
-                "INSERT INTO user (id, name, persona) VALUES (NULL, \"user\", \"{{user}} is chatting with {{char}} using ai-companion web user interface\")", []
-            )?;
+    }
+
+    // an index past the last greeting falls back to the first message
+    fn add_first_message(&self, con: &Connection, greeting: Greeting) -> Result<(), Error> {
+        let local: DateTime<Local> = Local::now();
+        let formatted_date = local.format("%A %d.%m.%Y %H:%M").to_string();
+        let companion = self.get_companion_data()?;
+        let user = self.get_user_data()?;
+        let index = match greeting {
+            Greeting::Index(i) => i,
+            Greeting::Random => rand::thread_rng().gen_range(0..=companion.alternate_greetings.len()),
+        };
+        let first_message = match index {
+            0 => &companion.first_message,
+            i => companion.alternate_greetings.get(i - 1).unwrap_or(&companion.first_message),
+        };
+        con.execute(
+            "INSERT INTO messages (id, ai, text, date, companion_id, user_id, chat_id) VALUES (NULL, \"true\", ?1, ?2, ?3, ?4, ?5)",
+            rusqlite::params![first_message.replace("{{char}}", &companion.name).replace("{{user}}", &user.name), formatted_date, self.companion_id, self.user_id, self.chat_id]
+        )?;
+        Ok(())
+    }
+
+    // new chats start with the chosen greeting of the companion and become the active chat
+    pub fn create_chat(&mut self, name: &str, greeting: Greeting) -> Result<u32, Error> {
+        let tx = self.con.unchecked_transaction()?;
+        let now = Database::timestamp();
+        tx.execute(
+            "INSERT INTO chat (id, name, companion_id, user_id, created, last_activity) VALUES (NULL, ?1, ?2, ?3, ?4, ?4)",
+            rusqlite::params![name, self.companion_id, self.user_id, now]
+        )?;
+        let chat_id = tx.last_insert_rowid() as u32;
+        let previous_chat_id = self.chat_id;
+        self.chat_id = chat_id;
+        if let Err(e) = self.add_first_message(&tx, greeting).and_then(|_| tx.commit()) {
+            self.chat_id = previous_chat_id;
+            return Err(e);
+        }
+        Ok(chat_id)
+    }
+
+    pub fn list_chats(&self) -> Result<Vec<ChatData>> {
+        let mut stmt = self.con.prepare("SELECT id, name, created, last_activity FROM chat WHERE companion_id=?1 AND user_id=?2 ORDER BY last_activity DESC, id DESC")?;
+        let chat_rows = stmt.query_map([self.companion_id, self.user_id], |row| {
+            Ok(ChatData {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                created: row.get(2)?,
+                last_activity: row.get(3)?,
+            })
+        })?;
+        let mut chats: Vec<ChatData> = Vec::new();
+        for chat in chat_rows {
+            chats.push(chat?);
+        }
+        Ok(chats)
+    }
+
+    pub fn select_chat(&mut self, chat_id: u32) -> Result<(), Error> {
+        self.con.query_row("SELECT id FROM chat WHERE id=?1 AND companion_id=?2 AND user_id=?3", [chat_id, self.companion_id, self.user_id], |row| row.get::<_, u32>(0))?;
+        self.chat_id = chat_id;
+        Ok(())
+    }
+
+    pub fn rename_chat(&self, chat_id: u32, name: &str) -> Result<(), Error> {
+        self.con.execute("UPDATE chat SET name=?1 WHERE id=?2 AND companion_id=?3 AND user_id=?4", rusqlite::params![name, chat_id, self.companion_id, self.user_id])?;
+        Ok(())
+    }
+
+    pub fn delete_chat(&self, chat_id: u32) -> Result<(), Error> {
+        let tx = self.con.unchecked_transaction()?;
+        tx.execute("DELETE FROM messages WHERE chat_id=?1 AND companion_id=?2 AND user_id=?3", [chat_id, self.companion_id, self.user_id])?;
+        tx.execute("DELETE FROM summaries WHERE chat_id=?1 AND companion_id=?2 AND user_id=?3", [chat_id, self.companion_id, self.user_id])?;
+        tx.execute("DELETE FROM chat WHERE id=?1 AND companion_id=?2 AND user_id=?3", [chat_id, self.companion_id, self.user_id])?;
+        Database::remove_orphaned_swipes(&tx)?;
+        tx.commit()
+    }
+
+    pub fn create_companion(&self, name: &str, persona: &str, example_dialogue: &str, first_message: &str) -> Result<u32, Error> {
+        Database::insert_companion(&self.con, name, persona, example_dialogue, first_message)
+    }
+
+    pub fn list_companions(&self) -> Result<Vec<CompanionData>> {
+        let mut stmt = self.con.prepare("SELECT * FROM companion ORDER BY id")?;
+        let companion_rows = stmt.query_map([], Database::companion_from_row)?;
+        let mut companions: Vec<CompanionData> = Vec::new();
+        for companion in companion_rows {
+            companions.push(companion?);
         }
-        if Database::is_table_empty("messages", &con) {
-            let local: DateTime<Local> = Local::now();
-            let formatted_date = local.format("%A %d.%m.%Y %H:%M").to_string();
-            let first_message: String = con.query_row("SELECT first_message FROM companion ASC LIMIT 1", [], |row| row.get(0))?;
-            con.execute(
-                &format!("INSERT INTO messages (id, ai, text, date) VALUES (NULL, \"true\", ?1, \"{}\")", formatted_date), [&first_message.replace("{{char}}", "Assistant").replace("{{user}}", "user")]
-            )
-        } else {
-            Ok(0)
+        Ok(companions)
+    }
+
+    pub fn companion_exists(&self, companion_id: u32) -> Result<bool, Error> {
+        self.con.query_row("SELECT EXISTS(SELECT 1 FROM companion WHERE id=?1)", [companion_id], |row| row.get(0))
+    }
+
+    // the previous companion stays selected if its chat can't be selected
+    pub fn select_companion(&mut self, companion_id: u32) -> Result<(), Error> {
+        self.con.query_row("SELECT id FROM companion WHERE id=?1", [companion_id], |row| row.get::<_, u32>(0))?;
+        let previous_companion_id = self.companion_id;
+        self.companion_id = companion_id;
+        if let Err(e) = self.select_latest_chat() {
+            self.companion_id = previous_companion_id;
+            return Err(e);
         }
+        Ok(())
+    }
+
+    pub fn delete_companion(&self, companion_id: u32) -> Result<(), Error> {
+        let tx = self.con.unchecked_transaction()?;
+        for table_name in ["messages", "chat", "sampling", "context_settings", "prompt_template", "memory_settings", "summaries", "lorebook"] {
+            tx.execute(&format!("DELETE FROM {} WHERE companion_id=?1", table_name), [companion_id])?;
+        }
+        tx.execute("DELETE FROM companion WHERE id=?1", [companion_id])?;
+        Database::remove_orphaned_swipes(&tx)?;
+        tx.commit()
+    }
+
+    pub fn create_user(&self, name: &str, persona: &str) -> Result<u32, Error> {
+        self.con.execute("INSERT INTO user (id, name, persona) VALUES (NULL, ?1, ?2)", [&name, &persona])?;
+        Ok(self.con.last_insert_rowid() as u32)
+    }
+
+    pub fn list_users(&self) -> Result<Vec<UserData>> {
+        let mut stmt = self.con.prepare("SELECT * FROM user ORDER BY id")?;
+        let user_rows = stmt.query_map([], Database::user_from_row)?;
+        let mut users: Vec<UserData> = Vec::new();
+        for user in user_rows {
+            users.push(user?);
+        }
+        Ok(users)
+    }
+
+    pub fn select_user(&mut self, user_id: u32) -> Result<(), Error> {
+        self.con.query_row("SELECT id FROM user WHERE id=?1", [user_id], |row| row.get::<_, u32>(0))?;
+        self.user_id = user_id;
+        self.select_latest_chat()
+    }
+
+    pub fn delete_user(&self, user_id: u32) -> Result<(), Error> {
+        let tx = self.con.unchecked_transaction()?;
+        tx.execute("DELETE FROM messages WHERE user_id=?1", [user_id])?;
+        tx.execute("DELETE FROM summaries WHERE user_id=?1", [user_id])?;
+        tx.execute("DELETE FROM chat WHERE user_id=?1", [user_id])?;
+        tx.execute("DELETE FROM user WHERE id=?1", [user_id])?;
+        Database::remove_orphaned_swipes(&tx)?;
+        tx.commit()
+    }
+
+    fn companion_from_row(row: &rusqlite::Row) -> Result<CompanionData> {
+        Ok(CompanionData {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            persona: row.get(2)?,
+            example_dialogue: row.get(3)?,
+            first_message: row.get(4)?,
+            long_term_mem: row.get(5)?,
+            short_term_mem: row.get(6)?,
+            roleplay: row.get(7)?,
+            avatar_path: row.get(8)?,
+            personality: row.get("personality")?,
+            scenario: row.get("scenario")?,
+            system_prompt: row.get("system_prompt")?,
+            post_history_instructions: row.get("post_history_instructions")?,
+            alternate_greetings: serde_json::from_str(&row.get::<_, String>("alternate_greetings")?).unwrap_or_default(),
+            tags: serde_json::from_str(&row.get::<_, String>("tags")?).unwrap_or_default(),
+            creator_notes: row.get("creator_notes")?,
+            extensions: row.get("extensions")?,
+        })
+    }
+
+    fn user_from_row(row: &rusqlite::Row) -> Result<UserData> {
+        Ok(UserData {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            persona: row.get(2)?,
+        })
     }
 
     pub fn is_table_empty(table_name: &str, con: &Connection) -> bool {
@@ -94,10 +442,9 @@ impl Database {
         count == 0
     }
 
-    pub fn get_messages() -> Result<Vec<Message>> {
-        let con = Connection::open("companion.db")?;
-        let mut stmt = con.prepare("SELECT id, ai, text, date FROM messages")?;
-        let message_rows = stmt.query_map([], |row| {
+    pub fn get_messages(&self) -> Result<Vec<Message>> {
+        let mut stmt = self.con.prepare("SELECT id, ai, text, date FROM messages WHERE chat_id=?1")?;
+        let message_rows = stmt.query_map([self.chat_id], |row| {
             Ok(Message {
                 id: row.get(0)?,
                 ai: row.get(1)?,
@@ -112,11 +459,10 @@ impl Database {
         Ok(messages)
     }
 
-    pub fn get_x_msgs(msgs_limit: u32) -> Result<Vec<Message>> {
-        let con = Connection::open("companion.db")?;
-        let mut stmt = con.prepare(&format!("SELECT id, ai, text, date FROM messages ORDER BY id DESC LIMIT {}", msgs_limit))?;
+    pub fn get_x_msgs(&self, msgs_limit: u32) -> Result<Vec<Message>> {
+        let mut stmt = self.con.prepare("SELECT id, ai, text, date FROM messages WHERE chat_id=?1 ORDER BY id DESC LIMIT ?2")?;
         let message_rows = stmt
-        .query_map([], |row| {
+        .query_map([self.chat_id, msgs_limit], |row| {
             Ok(Message {
                 id: row.get(0)?,
                 ai: row.get(1)?,
@@ -131,166 +477,662 @@ impl Database {
         Ok(messages.into_iter().rev().collect())
     }
 
-    pub fn get_companion_data() -> Result<CompanionData> {
-        let con = Connection::open("companion.db")?;
-        let mut stmt = con.prepare("SELECT * FROM companion LIMIT 1")?;
-        let companion_data = stmt
-        .query_map([], |row| {
-            Ok(CompanionData {
+    pub fn get_message(&self, id: u32) -> Result<Option<Message>> {
+        self.con.query_row("SELECT id, ai, text, date FROM messages WHERE id=?1 AND chat_id=?2", [id, self.chat_id], |row| {
+            Ok(Message {
                 id: row.get(0)?,
-                name: row.get(1)?,
-                persona: row.get(2)?,
-                example_dialogue: row.get(3)?,
-                first_message: row.get(4)?,
-                long_term_mem: row.get(5)?,
-                short_term_mem: row.get(6)?,
-                roleplay: row.get(7)?,
-                avatar_path: row.get(8)?,
+                ai: row.get(1)?,
+                text: row.get(2)?,
+                date: row.get(3)?,
             })
-        })?;
-        let mut result: CompanionData = Default::default();
-        for companion in companion_data {
-            result = companion?;
-         }
-        Ok(result)
-    }
-
-    pub fn get_user_data() -> Result<UserData> {
-        let con = Connection::open("companion.db")?;
-        let mut stmt = con.prepare("SELECT * FROM user LIMIT 1")?;
-        let user_data = stmt
-        .query_map([], |row| {
-            Ok(UserData {
+        }).optional()
+    }
+
+    // the message sent right after the message with the given id, for user messages this is the reply to them
+    pub fn get_next_message(&self, id: u32) -> Result<Option<Message>> {
+        self.con.query_row("SELECT id, ai, text, date FROM messages WHERE chat_id=?1 AND id>?2 ORDER BY id LIMIT 1", [self.chat_id, id], |row| {
+            Ok(Message {
                 id: row.get(0)?,
-                name: row.get(1)?,
-                persona: row.get(2)?,
+                ai: row.get(1)?,
+                text: row.get(2)?,
+                date: row.get(3)?,
+            })
+        }).optional()
+    }
+
+    // latest messages sent before the message with before_id, used to generate a reply again
+    pub fn get_x_msgs_before(&self, before_id: u32, msgs_limit: u32) -> Result<Vec<Message>> {
+        let mut stmt = self.con.prepare("SELECT id, ai, text, date FROM messages WHERE chat_id=?1 AND id<?2 ORDER BY id DESC LIMIT ?3")?;
+        let message_rows = stmt
+        .query_map([self.chat_id, before_id, msgs_limit], |row| {
+            Ok(Message {
+                id: row.get(0)?,
+                ai: row.get(1)?,
+                text: row.get(2)?,
+                date: row.get(3)?,
             })
         })?;
-        let mut result: UserData = Default::default();
-        for user in user_data {
-            result = user?;
-         }
-        Ok(result)
+        let mut messages: Vec<Message> = Vec::new();
+        for msgs in message_rows {
+           messages.push(msgs?);
+        }
+        Ok(messages.into_iter().rev().collect())
     }
 
-    pub fn add_message(text: &str, is_ai: bool) -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        let ai = &is_ai.to_string();
-        let local: DateTime<Local> = Local::now();
-        let formatted_date = &local.format("%A %d.%m.%Y %H:%M").to_string();
-        con.execute("INSERT INTO messages (id, ai, text, date) VALUES (NULL, ?1, ?2, ?3)", [&ai.as_str(), &text, &formatted_date.as_str()])?;
+    pub fn get_companion_data(&self) -> Result<CompanionData> {
+        self.con.query_row("SELECT * FROM companion WHERE id=?1", [self.companion_id], Database::companion_from_row)
+    }
+
+    pub fn get_user_data(&self) -> Result<UserData> {
+        self.con.query_row("SELECT * FROM user WHERE id=?1", [self.user_id], Database::user_from_row)
+    }
+
+    pub fn get_sampling_settings(&self) -> Result<SamplingSettings> {
+        self.con.query_row("SELECT temperature, top_k, top_p, repeat_penalty, repetition_window, seed, max_new_tokens FROM sampling WHERE companion_id=?1", [self.companion_id], |row| {
+            Ok(SamplingSettings {
+                temperature: row.get(0)?,
+                top_k: row.get(1)?,
+                top_p: row.get(2)?,
+                repeat_penalty: row.get(3)?,
+                repetition_window: row.get(4)?,
+                seed: row.get::<_, Option<i64>>(5)?.map(|v| v as u64),
+                max_new_tokens: row.get(6)?,
+            })
+        })
+    }
+
+    pub fn change_sampling_settings(&self, settings: &SamplingSettings) -> Result<(), Error> {
+        self.con.execute(
+            "UPDATE sampling SET temperature=?1, top_k=?2, top_p=?3, repeat_penalty=?4, repetition_window=?5, seed=?6, max_new_tokens=?7 WHERE companion_id=?8",
+            rusqlite::params![settings.temperature, settings.top_k, settings.top_p, settings.repeat_penalty, settings.repetition_window, settings.seed.map(|v| v as i64), settings.max_new_tokens, self.companion_id]
+        )?;
+        Ok(())
+    }
+
+    pub fn get_context_settings(&self) -> Result<ContextSettings> {
+        self.con.query_row("SELECT context_length, response_reserve, lorebook_budget FROM context_settings WHERE companion_id=?1", [self.companion_id], |row| {
+            Ok(ContextSettings {
+                context_length: row.get(0)?,
+                response_reserve: row.get(1)?,
+                lorebook_budget: row.get(2)?,
+            })
+        })
+    }
+
+    pub fn change_context_settings(&self, settings: &ContextSettings) -> Result<(), Error> {
+        self.con.execute(
+            "UPDATE context_settings SET context_length=?1, response_reserve=?2, lorebook_budget=?3 WHERE companion_id=?4",
+            rusqlite::params![settings.context_length, settings.response_reserve, settings.lorebook_budget, self.companion_id]
+        )?;
+        Ok(())
+    }
+
+    pub fn get_memory_settings(&self) -> Result<MemorySettings> {
+        self.con.query_row("SELECT retrieval, fusion, keyword_weight, vector_weight, min_score, summarize_after FROM memory_settings WHERE companion_id=?1", [self.companion_id], |row| {
+            Ok(MemorySettings {
+                retrieval: row.get(0)?,
+                fusion: row.get(1)?,
+                keyword_weight: row.get(2)?,
+                vector_weight: row.get(3)?,
+                min_score: row.get(4)?,
+                summarize_after: row.get(5)?,
+            })
+        })
+    }
+
+    pub fn change_memory_settings(&self, settings: &MemorySettings) -> Result<(), Error> {
+        self.con.execute(
+            "UPDATE memory_settings SET retrieval=?1, fusion=?2, keyword_weight=?3, vector_weight=?4, min_score=?5, summarize_after=?6 WHERE companion_id=?7",
+            rusqlite::params![settings.retrieval, settings.fusion, settings.keyword_weight, settings.vector_weight, settings.min_score, settings.summarize_after, self.companion_id]
+        )?;
         Ok(())
     }
 
-    pub fn modify_message(text: &str, msg_id: u32) -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        con.execute("UPDATE messages SET text = ? WHERE id = ?", [&text, &msg_id.to_string().as_str()])?;
+    pub fn get_prompt_template(&self) -> Result<PromptTemplate> {
+        let (name, context, user_message, ai_message, stop_sequences): (String, String, String, String, String) =
+            self.con.query_row("SELECT name, context, user_message, ai_message, stop_sequences FROM prompt_template WHERE companion_id=?1", [self.companion_id], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+            })?;
+        if let Some(template) = PromptTemplate::builtin(&name) {
+            return Ok(template);
+        }
+        let stop_sequences: Vec<String> = serde_json::from_str(&stop_sequences)
+            .map_err(|e| Error::FromSqlConversionFailure(4, rusqlite::types::Type::Text, Box::new(e)))?;
+        Ok(PromptTemplate { name, context, user_message, ai_message, stop_sequences, reply_prefix: None })
+    }
+
+    // templates that were never chosen have no context stored, they follow the loaded model
+    pub fn change_default_prompt_template(&self, name: &str) -> Result<(), Error> {
+        self.con.execute("UPDATE prompt_template SET name=?1 WHERE context=\"\"", [name])?;
         Ok(())
     }
 
-    pub fn remove_latest_message() -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        con.execute("DELETE FROM messages WHERE rowid = (SELECT max(rowid) FROM messages)", [])?;
+    pub fn change_prompt_template(&self, template: &PromptTemplate) -> Result<(), Error> {
+        let stop_sequences = serde_json::to_string(&template.stop_sequences)
+            .map_err(|e| Error::ToSqlConversionFailure(Box::new(e)))?;
+        self.con.execute(
+            "UPDATE prompt_template SET name=?1, context=?2, user_message=?3, ai_message=?4, stop_sequences=?5 WHERE companion_id=?6",
+            rusqlite::params![template.name, template.context, template.user_message, template.ai_message, stop_sequences, self.companion_id]
+        )?;
         Ok(())
     }
 
-    pub fn clear_messages() -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        con.execute("DELETE FROM messages", [])?;
-        let first_message: String = con.query_row("SELECT first_message FROM companion ASC LIMIT 1", [], |row| row.get(0))?;
+    // returns id of the new message
+    pub fn add_message(&self, text: &str, is_ai: bool) -> Result<u32, Error> {
+        let ids = self.add_messages(&[(text, is_ai)])?;
+        Ok(ids[0])
+    }
+
+    // adds all messages in one transaction, returns their ids
+    pub fn add_messages(&self, messages: &[(&str, bool)]) -> Result<Vec<u32>, Error> {
+        let tx = self.con.unchecked_transaction()?;
         let local: DateTime<Local> = Local::now();
         let formatted_date = &local.format("%A %d.%m.%Y %H:%M").to_string();
-        let companion = Database::get_companion_data()?;
-        let user = Database::get_user_data()?;
+        let mut ids: Vec<u32> = Vec::new();
+        for (text, is_ai) in messages {
+            tx.execute("INSERT INTO messages (id, ai, text, date, companion_id, user_id, chat_id) VALUES (NULL, ?1, ?2, ?3, ?4, ?5, ?6)", rusqlite::params![is_ai.to_string(), text, formatted_date, self.companion_id, self.user_id, self.chat_id])?;
+            ids.push(tx.last_insert_rowid() as u32);
+        }
+        tx.execute("UPDATE chat SET last_activity=?1 WHERE id=?2", rusqlite::params![Database::timestamp(), self.chat_id])?;
+        tx.commit()?;
+        Ok(ids)
+    }
+
+    // the latest summary covers every summarized message of the chat
+    pub fn get_latest_summary(&self) -> Result<Option<Summary>> {
+        self.con.query_row(
+            "SELECT id, text, first_message_id, last_message_id, created FROM summaries WHERE chat_id=?1 ORDER BY last_message_id DESC, id DESC LIMIT 1",
+            [self.chat_id], |row| {
+            Ok(Summary {
+                id: row.get(0)?,
+                text: row.get(1)?,
+                first_message_id: row.get(2)?,
+                last_message_id: row.get(3)?,
+                created: row.get(4)?,
+            })
+        }).optional()
+    }
+
+    pub fn add_summary(&self, text: &str, first_message_id: u32, last_message_id: u32) -> Result<u32, Error> {
+        self.con.execute(
+            "INSERT INTO summaries (id, text, first_message_id, last_message_id, created, companion_id, user_id, chat_id) VALUES (NULL, ?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            rusqlite::params![text, first_message_id, last_message_id, Database::timestamp(), self.companion_id, self.user_id, self.chat_id]
+        )?;
+        Ok(self.con.last_insert_rowid() as u32)
+    }
+
+    // messages newer than after_id, without the latest keep_latest messages (they are still in short-term memory)
+    pub fn get_unsummarized_messages(&self, after_id: u32, keep_latest: u32) -> Result<Vec<Message>> {
+        let mut stmt = self.con.prepare(
+            "SELECT id, ai, text, date FROM messages WHERE chat_id=?1 AND id>?2
+                AND id NOT IN (SELECT id FROM messages WHERE chat_id=?1 ORDER BY id DESC LIMIT ?3) ORDER BY id"
+        )?;
+        let message_rows = stmt.query_map([self.chat_id, after_id, keep_latest], |row| {
+            Ok(Message {
+                id: row.get(0)?,
+                ai: row.get(1)?,
+                text: row.get(2)?,
+                date: row.get(3)?,
+            })
+        })?;
+        let mut messages: Vec<Message> = Vec::new();
+        for msgs in message_rows {
+           messages.push(msgs?);
+        }
+        Ok(messages)
+    }
+
+    pub fn get_lorebook_entries(&self) -> Result<Vec<LorebookEntry>> {
+        let mut stmt = self.con.prepare(
+            "SELECT id, name, keys, secondary_keys, content, insertion_order, position, scan_depth, enabled, constant, case_sensitive
+                FROM lorebook WHERE companion_id=?1 ORDER BY insertion_order, id"
+        )?;
+        let entry_rows = stmt.query_map([self.companion_id], |row| {
+            Ok(LorebookEntry {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                keys: serde_json::from_str(&row.get::<_, String>(2)?).unwrap_or_default(),
+                secondary_keys: serde_json::from_str(&row.get::<_, String>(3)?).unwrap_or_default(),
+                content: row.get(4)?,
+                insertion_order: row.get(5)?,
+                position: row.get(6)?,
+                scan_depth: row.get(7)?,
+                enabled: row.get(8)?,
+                constant: row.get(9)?,
+                case_sensitive: row.get(10)?,
+            })
+        })?;
+        let mut entries: Vec<LorebookEntry> = Vec::new();
+        for entry in entry_rows {
+            entries.push(entry?);
+        }
+        Ok(entries)
+    }
+
+    fn insert_lorebook_entry(&self, con: &Connection, entry: &LorebookEntry) -> Result<u32, Error> {
+        let keys = serde_json::to_string(&entry.keys).map_err(|e| Error::ToSqlConversionFailure(Box::new(e)))?;
+        let secondary_keys = serde_json::to_string(&entry.secondary_keys).map_err(|e| Error::ToSqlConversionFailure(Box::new(e)))?;
         con.execute(
-            &format!("INSERT INTO messages (id, ai, text, date) VALUES (NULL, \"true\", ?1, \"{}\")", formatted_date), [&first_message.replace("{{char}}", &companion.name).replace("{{user}}", &user.name)]
+            "INSERT INTO lorebook (id, name, keys, secondary_keys, content, insertion_order, position, scan_depth, enabled, constant, case_sensitive, companion_id)
+                VALUES (NULL, ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            rusqlite::params![entry.name, keys, secondary_keys, entry.content, entry.insertion_order, entry.position, entry.scan_depth, entry.enabled, entry.constant, entry.case_sensitive, self.companion_id]
         )?;
+        Ok(con.last_insert_rowid() as u32)
+    }
+
+    pub fn add_lorebook_entry(&self, entry: &LorebookEntry) -> Result<u32, Error> {
+        self.insert_lorebook_entry(&self.con, entry)
+    }
+
+    // adds all entries in one transaction, existing entries are removed first if replace is set
+    pub fn add_lorebook_entries(&self, entries: &[LorebookEntry], replace: bool) -> Result<Vec<u32>, Error> {
+        let tx = self.con.unchecked_transaction()?;
+        if replace {
+            tx.execute("DELETE FROM lorebook WHERE companion_id=?1", [self.companion_id])?;
+        }
+        let mut ids: Vec<u32> = Vec::new();
+        for entry in entries {
+            ids.push(self.insert_lorebook_entry(&tx, entry)?);
+        }
+        tx.commit()?;
+        Ok(ids)
+    }
+
+    // returns false if the companion has no entry with the id
+    pub fn change_lorebook_entry(&self, entry: &LorebookEntry) -> Result<bool, Error> {
+        let keys = serde_json::to_string(&entry.keys).map_err(|e| Error::ToSqlConversionFailure(Box::new(e)))?;
+        let secondary_keys = serde_json::to_string(&entry.secondary_keys).map_err(|e| Error::ToSqlConversionFailure(Box::new(e)))?;
+        let changed = self.con.execute(
+            "UPDATE lorebook SET name=?1, keys=?2, secondary_keys=?3, content=?4, insertion_order=?5, position=?6, scan_depth=?7, enabled=?8, constant=?9, case_sensitive=?10
+                WHERE id=?11 AND companion_id=?12",
+            rusqlite::params![entry.name, keys, secondary_keys, entry.content, entry.insertion_order, entry.position, entry.scan_depth, entry.enabled, entry.constant, entry.case_sensitive, entry.id, self.companion_id]
+        )?;
+        Ok(changed > 0)
+    }
+
+    // returns false if the companion has no entry with the id
+    pub fn remove_lorebook_entry(&self, id: u32) -> Result<bool, Error> {
+        let removed = self.con.execute("DELETE FROM lorebook WHERE id=?1 AND companion_id=?2", [id, self.companion_id])?;
+        Ok(removed > 0)
+    }
+
+    // the selected swipe of the message is changed too
+    pub fn modify_message(&self, text: &str, msg_id: u32) -> Result<(), Error> {
+        let tx = self.con.unchecked_transaction()?;
+        tx.execute("UPDATE messages SET text = ?1 WHERE id = ?2 AND chat_id = ?3", rusqlite::params![text, msg_id, self.chat_id])?;
+        tx.execute(
+            "UPDATE swipes SET text = ?1 WHERE id = (SELECT id FROM swipes WHERE message_id = ?2 ORDER BY id LIMIT 1 OFFSET (SELECT swipe FROM messages WHERE id = ?2 AND chat_id = ?3))",
+            rusqlite::params![text, msg_id, self.chat_id]
+        )?;
+        tx.commit()
+    }
+
+    fn remove_orphaned_swipes(con: &Connection) -> Result<(), Error> {
+        con.execute("DELETE FROM swipes WHERE message_id NOT IN (SELECT id FROM messages)", [])?;
         Ok(())
     }
 
-    pub fn change_first_message(first_message: &str) -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        con.execute(&format!("UPDATE companion SET first_message=\"{}\"", first_message), [])?;
+    // a message that was never swiped has its own text as the only swipe
+    pub fn get_swipes(&self, message_id: u32) -> Result<Vec<Swipe>> {
+        let (text, date, selected): (String, String, usize) = self.con.query_row(
+            "SELECT text, date, swipe FROM messages WHERE id=?1 AND chat_id=?2", [message_id, self.chat_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        )?;
+        let mut stmt = self.con.prepare("SELECT text, created FROM swipes WHERE message_id=?1 ORDER BY id")?;
+        let swipe_rows = stmt.query_map([message_id], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+        let mut swipes: Vec<Swipe> = Vec::new();
+        for (index, swipe) in swipe_rows.enumerate() {
+            let (text, created) = swipe?;
+            swipes.push(Swipe { index, text, created, selected: index == selected });
+        }
+        if swipes.is_empty() {
+            swipes.push(Swipe { index: 0, text, created: date, selected: true });
+        }
+        Ok(swipes)
+    }
+
+    // the new swipe becomes the text of the message, returns its index
+    pub fn add_swipe(&self, message_id: u32, text: &str) -> Result<usize, Error> {
+        let tx = self.con.unchecked_transaction()?;
+        let (current_text, date): (String, String) = tx.query_row(
+            "SELECT text, date FROM messages WHERE id=?1 AND chat_id=?2", [message_id, self.chat_id],
+            |row| Ok((row.get(0)?, row.get(1)?))
+        )?;
+        let count: usize = tx.query_row("SELECT COUNT(*) FROM swipes WHERE message_id=?1", [message_id], |row| row.get(0))?;
+        if count == 0 {
+            tx.execute("INSERT INTO swipes (id, message_id, text, created) VALUES (NULL, ?1, ?2, ?3)", rusqlite::params![message_id, current_text, date])?;
+        }
+        tx.execute("INSERT INTO swipes (id, message_id, text, created) VALUES (NULL, ?1, ?2, ?3)", rusqlite::params![message_id, text, Database::timestamp()])?;
+        let index = count.max(1);
+        tx.execute("UPDATE messages SET text=?1, swipe=?2 WHERE id=?3", rusqlite::params![text, index, message_id])?;
+        tx.commit()?;
+        Ok(index)
+    }
+
+    // returns the text of the selected swipe or None if there is no swipe with that index
+    pub fn select_swipe(&self, message_id: u32, index: usize) -> Result<Option<String>, Error> {
+        let tx = self.con.unchecked_transaction()?;
+        let text: Option<String> = tx.query_row(
+            "SELECT swipes.text FROM swipes JOIN messages ON messages.id=swipes.message_id WHERE swipes.message_id=?1 AND messages.chat_id=?2 ORDER BY swipes.id LIMIT 1 OFFSET ?3",
+            rusqlite::params![message_id, self.chat_id, index], |row| row.get(0)
+        ).optional()?;
+        if let Some(text) = &text {
+            tx.execute("UPDATE messages SET text=?1, swipe=?2 WHERE id=?3", rusqlite::params![text, index, message_id])?;
+            tx.commit()?;
+            return Ok(Some(text.clone()));
+        }
+        // the only swipe of a message that was never swiped is the message itself
+        match index {
+            0 => Ok(self.get_message(message_id)?.map(|message| message.text)),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn clear_messages(&self, greeting: Greeting) -> Result<(), Error> {
+        let tx = self.con.unchecked_transaction()?;
+        tx.execute("DELETE FROM messages WHERE chat_id=?1", [self.chat_id])?;
+        tx.execute("DELETE FROM summaries WHERE chat_id=?1", [self.chat_id])?;
+        Database::remove_orphaned_swipes(&tx)?;
+        self.add_first_message(&tx, greeting)?;
+        tx.commit()
+    }
+
+    // the first message followed by the alternate greetings
+    pub fn get_greetings(&self) -> Result<Vec<String>> {
+        let companion = self.get_companion_data()?;
+        let mut greetings = vec![companion.first_message];
+        greetings.extend(companion.alternate_greetings);
+        Ok(greetings)
+    }
+
+    fn change_alternate_greetings(&self, alternate_greetings: &[String]) -> Result<(), Error> {
+        let alternate_greetings = serde_json::to_string(alternate_greetings).map_err(|e| Error::ToSqlConversionFailure(Box::new(e)))?;
+        self.con.execute("UPDATE companion SET alternate_greetings=?1 WHERE id=?2", rusqlite::params![alternate_greetings, self.companion_id])?;
         Ok(())
     }
 
-    pub fn change_companion_name(name: &str) -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        con.execute(&format!("UPDATE companion SET name=\"{}\"", name), [])?;
+    // returns the index of the new greeting
+    pub fn add_greeting(&self, text: &str) -> Result<usize, Error> {
+        let mut alternate_greetings = self.get_companion_data()?.alternate_greetings;
+        alternate_greetings.push(text.to_string());
+        self.change_alternate_greetings(&alternate_greetings)?;
+        Ok(alternate_greetings.len())
+    }
+
+    // returns false if there is no greeting with that index
+    pub fn change_greeting(&self, index: usize, text: &str) -> Result<bool, Error> {
+        if index == 0 {
+            self.change_first_message(text)?;
+            return Ok(true);
+        }
+        let mut alternate_greetings = self.get_companion_data()?.alternate_greetings;
+        match alternate_greetings.get_mut(index - 1) {
+            Some(greeting) => *greeting = text.to_string(),
+            None => return Ok(false),
+        };
+        self.change_alternate_greetings(&alternate_greetings)?;
+        Ok(true)
+    }
+
+    // the first message can't be removed, only the alternate greetings
+    pub fn remove_greeting(&self, index: usize) -> Result<bool, Error> {
+        let mut alternate_greetings = self.get_companion_data()?.alternate_greetings;
+        if index == 0 || index > alternate_greetings.len() {
+            return Ok(false);
+        }
+        alternate_greetings.remove(index - 1);
+        self.change_alternate_greetings(&alternate_greetings)?;
+        Ok(true)
+    }
+
+    pub fn change_first_message(&self, first_message: &str) -> Result<(), Error> {
+        self.con.execute("UPDATE companion SET first_message=?1 WHERE id=?2", rusqlite::params![first_message, self.companion_id])?;
         Ok(())
     }
 
-    pub fn change_companion_persona(persona: &str) -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        con.execute(&format!("UPDATE companion SET persona=\"{}\"", persona), [])?;
+    pub fn change_companion_name(&self, name: &str) -> Result<(), Error> {
+        self.con.execute("UPDATE companion SET name=?1 WHERE id=?2", rusqlite::params![name, self.companion_id])?;
         Ok(())
     }
 
-    pub fn change_companion_example_dialogue(example_dialogue: &str) -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        con.execute(&format!("UPDATE companion SET example_dialogue=\"{}\"", example_dialogue), [])?;
+    pub fn change_companion_persona(&self, persona: &str) -> Result<(), Error> {
+        self.con.execute("UPDATE companion SET persona=?1 WHERE id=?2", rusqlite::params![persona, self.companion_id])?;
         Ok(())
     }
 
-    pub fn change_companion(name: &str, persona: &str, example_dialogue: &str, first_message: &str, long_term_mem: u32, short_term_mem: u32, roleplay: bool) -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        con.execute(&format!("UPDATE companion SET name=?1, persona=?2, example_dialogue=?3, first_message=?4, long_term_mem={}, short_term_mem={}, roleplay={}", long_term_mem, short_term_mem, roleplay), [&name, &persona, &example_dialogue, &first_message])?;
+    pub fn change_companion_example_dialogue(&self, example_dialogue: &str) -> Result<(), Error> {
+        self.con.execute("UPDATE companion SET example_dialogue=?1 WHERE id=?2", rusqlite::params![example_dialogue, self.companion_id])?;
+        Ok(())
+    }
+
+    // the python api takes every field as a separate argument
+    #[allow(clippy::too_many_arguments)]
+    pub fn change_companion(&self, name: &str, persona: &str, example_dialogue: &str, first_message: &str, long_term_mem: u32, short_term_mem: u32, roleplay: bool) -> Result<(), Error> {
+        self.con.execute("UPDATE companion SET name=?1, persona=?2, example_dialogue=?3, first_message=?4, long_term_mem=?5, short_term_mem=?6, roleplay=?7 WHERE id=?8", rusqlite::params![name, persona, example_dialogue, first_message, long_term_mem, short_term_mem, roleplay, self.companion_id])?;
         Ok(())
     }
 
     /*
-    pub fn change_companion_avatar(path: &str) -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        con.execute(&format!("UPDATE companion SET avatar_path=\"{}\"", path), [])?;
+    pub fn change_companion_avatar(&self, path: &str) -> Result<(), Error> {
+        self.con.execute("UPDATE companion SET avatar_path=?1 WHERE id=?2", rusqlite::params![path, self.companion_id])?;
         Ok(())
     }
     */
 
-    pub fn import_companion(name: &str, persona: &str, example_dialogue: &str, first_message: &str) -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        con.execute("UPDATE companion SET name=?1, persona=?2, example_dialogue=?3, first_message=?4", [&name, &persona, &example_dialogue, &first_message])?;
+    pub fn import_companion(&self, card: &CharacterCardData) -> Result<(), Error> {
+        let alternate_greetings = serde_json::to_string(&card.alternate_greetings).map_err(|e| Error::ToSqlConversionFailure(Box::new(e)))?;
+        let tags = serde_json::to_string(&card.tags).map_err(|e| Error::ToSqlConversionFailure(Box::new(e)))?;
+        let extensions = serde_json::to_string(&card.extensions).map_err(|e| Error::ToSqlConversionFailure(Box::new(e)))?;
+        self.con.execute(
+            "UPDATE companion SET name=?1, persona=?2, example_dialogue=?3, first_message=?4, personality=?5, scenario=?6, system_prompt=?7,
+                post_history_instructions=?8, alternate_greetings=?9, tags=?10, creator_notes=?11, extensions=?12 WHERE id=?13",
+            rusqlite::params![card.name, card.description, card.mes_example, card.first_mes, card.personality, card.scenario, card.system_prompt,
+                card.post_history_instructions, alternate_greetings, tags, card.creator_notes, extensions, self.companion_id]
+        )?;
         Ok(())
     }
 
-    pub fn rm_message(id: u32) -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        con.execute(&format!("DELETE FROM messages WHERE id={}", id), [])?;
-        Ok(())
+    pub fn rm_message(&self, id: u32) -> Result<(), Error> {
+        let tx = self.con.unchecked_transaction()?;
+        tx.execute("DELETE FROM messages WHERE id=?1 AND chat_id=?2", [id, self.chat_id])?;
+        Database::remove_orphaned_swipes(&tx)?;
+        tx.commit()
     }
 
-    pub fn change_username(name: &str) -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        con.execute(&format!("UPDATE user SET name=\"{}\"", name), [])?;
+    pub fn change_username(&self, name: &str) -> Result<(), Error> {
+        self.con.execute("UPDATE user SET name=?1 WHERE id=?2", rusqlite::params![name, self.user_id])?;
         Ok(())
     }
 
-    pub fn change_user_persona(persona: &str) -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        con.execute(&format!("UPDATE user SET persona=\"{}\"", persona), [])?;
+    pub fn change_user_persona(&self, persona: &str) -> Result<(), Error> {
+        self.con.execute("UPDATE user SET persona=?1 WHERE id=?2", rusqlite::params![persona, self.user_id])?;
         Ok(())
     }
 
-    pub fn change_user(name: &str, persona: &str) -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        con.execute(&format!("UPDATE user SET name=\"{}\", persona=\"{}\"", name, persona), [])?;
+    pub fn change_user(&self, name: &str, persona: &str) -> Result<(), Error> {
+        self.con.execute("UPDATE user SET name=?1, persona=?2 WHERE id=?3", rusqlite::params![name, persona, self.user_id])?;
         Ok(())
     }
 
-    pub fn change_short_term_memory(limit: u32) -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        con.execute(&format!("UPDATE companion SET short_term_mem={}", limit), [])?;
+    pub fn change_short_term_memory(&self, limit: u32) -> Result<(), Error> {
+        self.con.execute("UPDATE companion SET short_term_mem=?1 WHERE id=?2", [limit, self.companion_id])?;
         Ok(())
     }
 
-    pub fn change_long_term_memory(limit: u32) -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        con.execute(&format!("UPDATE companion SET long_term_mem={}", limit), [])?;
+    pub fn change_long_term_memory(&self, limit: u32) -> Result<(), Error> {
+        self.con.execute("UPDATE companion SET long_term_mem=?1 WHERE id=?2", [limit, self.companion_id])?;
         Ok(())
     }
 
-    pub fn disable_enable_roleplay(op: bool) -> Result<(), Error> {
-        let con = Connection::open("companion.db")?;
-        con.execute(&format!("UPDATE companion SET roleplay={}", op), [])?;
+    pub fn disable_enable_roleplay(&self, op: bool) -> Result<(), Error> {
+        self.con.execute("UPDATE companion SET roleplay=?1 WHERE id=?2", rusqlite::params![op, self.companion_id])?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::types::Value;
+
+    // quotes, statement separators, comments and multi-byte characters have to be stored as they are
+    const TRICKY_STRINGS: [&str; 7] = [
+        "\"quoted\"",
+        "it's",
+        "first; second",
+        "text -- comment",
+        "\"; DROP TABLE companion; --",
+        "'); DELETE FROM user; --",
+        "zażółć gęślą jaźń, 日本語, 🙂",
+    ];
+
+    // a second companion and user whose rows must never change
+    fn database() -> (Database, u32, u32) {
+        let mut database = Database::new(Path::new(":memory:")).unwrap();
+        database.create().unwrap();
+        let other_companion_id = database.create_companion("Other", "other persona", "other dialogue", "other greeting").unwrap();
+        let other_user_id = database.create_user("Other user", "other user persona").unwrap();
+        (database, other_companion_id, other_user_id)
+    }
+
+    // every row of the table except the one with the given id
+    fn other_rows(database: &Database, table_name: &str, except_id: u32) -> Vec<Vec<Value>> {
+        let mut stmt = database.con.prepare(&format!("SELECT * FROM {} WHERE id!=?1 ORDER BY id", table_name)).unwrap();
+        let column_count = stmt.column_count();
+        let rows = stmt.query_map([except_id], |row| (0..column_count).map(|i| row.get::<_, Value>(i)).collect::<Result<Vec<Value>>>()).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+
+    fn assert_tables_exist(database: &Database) {
+        for table_name in ["companion", "user", "messages", "chat"] {
+            assert!(migrations::has_column(table_name, "id", &database.con).unwrap(), "table {} is missing", table_name);
+        }
+    }
+
+    #[test]
+    fn companion_setters_store_text_as_is() {
+        let (database, _, _) = database();
+        for text in TRICKY_STRINGS {
+            let companions = other_rows(&database, "companion", database.companion_id);
+            let users = other_rows(&database, "user", 0);
+            database.change_companion_name(text).unwrap();
+            database.change_companion_persona(text).unwrap();
+            database.change_companion_example_dialogue(text).unwrap();
+            database.change_first_message(text).unwrap();
+            let companion = database.get_companion_data().unwrap();
+            assert_eq!(companion.name.as_bytes(), text.as_bytes());
+            assert_eq!(companion.persona.as_bytes(), text.as_bytes());
+            assert_eq!(companion.example_dialogue.as_bytes(), text.as_bytes());
+            assert_eq!(companion.first_message.as_bytes(), text.as_bytes());
+            database.change_companion(&format!("{} name", text), &format!("{} persona", text), &format!("{} dialogue", text), &format!("{} greeting", text), 3, 7, false).unwrap();
+            let companion = database.get_companion_data().unwrap();
+            assert_eq!(companion.name, format!("{} name", text));
+            assert_eq!(companion.persona, format!("{} persona", text));
+            assert_eq!(companion.example_dialogue, format!("{} dialogue", text));
+            assert_eq!(companion.first_message, format!("{} greeting", text));
+            assert_eq!((companion.long_term_mem, companion.short_term_mem, companion.roleplay), (3, 7, 0));
+            assert_eq!(other_rows(&database, "companion", database.companion_id), companions);
+            assert_eq!(other_rows(&database, "user", 0), users);
+            assert_tables_exist(&database);
+        }
+    }
+
+    #[test]
+    fn selecting_a_missing_companion_keeps_the_active_one() {
+        let (mut database, _, _) = database();
+        let (companion_id, chat_id) = (database.companion_id, database.chat_id);
+        assert!(database.companion_exists(companion_id).unwrap());
+        assert!(!database.companion_exists(companion_id + 100).unwrap());
+        assert!(database.select_companion(companion_id + 100).is_err());
+        assert_eq!((database.companion_id, database.chat_id), (companion_id, chat_id));
+        let other_id = database.create_companion("Other", "", "", "Hi").unwrap();
+        database.select_companion(other_id).unwrap();
+        assert_eq!(database.companion_id, other_id);
+        assert_ne!(database.chat_id, chat_id);
+    }
+
+    #[test]
+    fn user_setters_store_text_as_is() {
+        let (database, _, _) = database();
+        for text in TRICKY_STRINGS {
+            let users = other_rows(&database, "user", database.user_id);
+            let companions = other_rows(&database, "companion", 0);
+            database.change_username(text).unwrap();
+            database.change_user_persona(text).unwrap();
+            let user = database.get_user_data().unwrap();
+            assert_eq!(user.name.as_bytes(), text.as_bytes());
+            assert_eq!(user.persona.as_bytes(), text.as_bytes());
+            database.change_user(&format!("{} name", text), &format!("{} persona", text)).unwrap();
+            let user = database.get_user_data().unwrap();
+            assert_eq!(user.name, format!("{} name", text));
+            assert_eq!(user.persona, format!("{} persona", text));
+            assert_eq!(other_rows(&database, "user", database.user_id), users);
+            assert_eq!(other_rows(&database, "companion", 0), companions);
+            assert_tables_exist(&database);
+        }
+    }
+
+    #[test]
+    fn new_companions_users_and_chats_store_text_as_is() {
+        let (mut database, _, _) = database();
+        for text in TRICKY_STRINGS {
+            let companion_id = database.create_companion(text, text, text, text).unwrap();
+            let companion = database.list_companions().unwrap().into_iter().find(|c| c.id == companion_id).unwrap();
+            assert_eq!((companion.name.as_str(), companion.persona.as_str(), companion.example_dialogue.as_str(), companion.first_message.as_str()), (text, text, text, text));
+            let user_id = database.create_user(text, text).unwrap();
+            let user = database.list_users().unwrap().into_iter().find(|u| u.id == user_id).unwrap();
+            assert_eq!((user.name.as_str(), user.persona.as_str()), (text, text));
+            let chats = other_rows(&database, "chat", 0);
+            let chat_id = database.create_chat(text, Greeting::Index(0)).unwrap();
+            assert_eq!(other_rows(&database, "chat", chat_id), chats);
+            database.rename_chat(chat_id, &format!("{} renamed", text)).unwrap();
+            let chat = database.list_chats().unwrap().into_iter().find(|c| c.id == chat_id).unwrap();
+            assert_eq!(chat.name, format!("{} renamed", text));
+            assert_eq!(other_rows(&database, "chat", chat_id), chats);
+            assert_tables_exist(&database);
+        }
+    }
+
+    #[test]
+    fn messages_store_text_as_is() {
+        let (database, _, _) = database();
+        for text in TRICKY_STRINGS {
+            let message_id = database.add_message(text, false).unwrap();
+            let latest = database.get_x_msgs(1).unwrap();
+            assert_eq!(latest.len(), 1);
+            assert_eq!((latest[0].id, latest[0].text.as_bytes()), (message_id, text.as_bytes()));
+            let messages = other_rows(&database, "messages", message_id);
+            database.modify_message(&format!("{} edited", text), message_id).unwrap();
+            assert_eq!(database.get_x_msgs(1).unwrap()[0].text, format!("{} edited", text));
+            assert_eq!(other_rows(&database, "messages", message_id), messages);
+            database.rm_message(message_id).unwrap();
+            assert_eq!(other_rows(&database, "messages", message_id), messages);
+            assert!(database.get_message(message_id).unwrap().is_none());
+            assert_tables_exist(&database);
+        }
+    }
+
+    #[test]
+    fn greetings_store_text_as_is() {
+        let (database, _, _) = database();
+        let companions = other_rows(&database, "companion", database.companion_id);
+        for text in TRICKY_STRINGS {
+            let index = database.add_greeting(text).unwrap();
+            assert_eq!(database.get_greetings().unwrap()[index], text);
+            assert!(database.change_greeting(index, &format!("{} edited", text)).unwrap());
+            assert_eq!(database.get_greetings().unwrap()[index], format!("{} edited", text));
+        }
+        assert_eq!(other_rows(&database, "companion", database.companion_id), companions);
+    }
+
+    #[test]
+    fn default_prompt_template_follows_the_model_until_chosen() {
+        let (database, _, _) = database();
+        assert_eq!(database.get_prompt_template().unwrap().name, "pygmalion");
+        database.change_default_prompt_template("llama2").unwrap();
+        assert_eq!(database.get_prompt_template().unwrap().name, "llama2");
+        database.change_prompt_template(&PromptTemplate::builtin("chatml").unwrap()).unwrap();
+        database.change_default_prompt_template("pygmalion").unwrap();
+        assert_eq!(database.get_prompt_template().unwrap().name, "chatml");
+    }
+}
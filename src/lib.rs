@@ -1,76 +1,221 @@
 use pyo3::prelude::*;
 use llm::LoadProgress;
-use std::io::Read;
-use std::fs::File;
-use base64::{Engine, engine::GeneralPurpose, engine::GeneralPurposeConfig, alphabet::STANDARD};
+use std::io::{Read, Write};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
 mod database;
-use database::{Database, CompanionData, UserData};
+mod migrations;
+use database::{Database, CompanionData, UserData, ChatData, SamplingSettings, ContextSettings, MemorySettings, Summary, Greeting, Message, Swipe};
+use pyo3::types::PyDict;
 mod vectordb;
-use vectordb::VectorDatabase;
+use vectordb::{VectorDatabase, MemoryEntry, MemoryFilter, RETRIEVAL_MODES, FUSION_METHODS, MEMORY_SOURCES, MEMORY_SORTING};
+mod template;
+use template::{PromptTemplate, TEMPLATE_NAMES};
 mod prompt;
-use prompt::{prompt_rs, Companion};
+mod summary;
+mod character;
+mod lorebook;
+use lorebook::{LorebookEntry, LOREBOOK_POSITIONS, entries_from_character_book, character_book_from_entries};
+use character::{parse_character_json, read_character_card, write_character_card, card_from_companion};
+use prompt::{prompt_rs, remember_reply, Companion, ContextReport, Reply};
 
 #[pymethods]
 impl Companion {
-    fn load_model(&mut self, ai_model_path: &str, use_gpu: bool) -> PyResult<()> {
-        if ai_model_path.ends_with(".bin") {
-            self.is_llama2 = ai_model_path.contains("llama");
-        } else {
-            return Err(pyo3::exceptions::PyValueError::new_err("Error while loading ai model, make sure that the path to the ai model is correct, that it is a valid GGML model and that the file has a .bin extension"));
-        }
-        let llama = llm::load::<llm::models::Llama>(
-            std::path::Path::new(ai_model_path),
-            llm::TokenizerSource::Embedded,
-            llm::ModelParameters {
-                prefer_mmap: true,
-                use_gpu: use_gpu,
-                ..Default::default()
+    // without architecture it is guessed from the file name, llama if the name doesn't contain one
+    #[pyo3(signature = (ai_model_path, use_gpu, architecture=None))]
+    fn load_model(&mut self, ai_model_path: &str, use_gpu: bool, architecture: Option<&str>) -> PyResult<()> {
+        let context_settings = match self.database.get_context_settings() {
+            Ok(cs) => cs,
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting context settings from sqlite database: {:?}", e)));
+            }
+        };
+        self.ai_model = Some(load_ggml_model(ai_model_path, use_gpu, architecture, context_settings.context_length)?);
+        // companions without a chosen prompt template keep the prompt format of older versions
+        let default_template = if ai_model_path.contains("llama") { "llama2" } else { "pygmalion" };
+        match self.database.change_default_prompt_template(default_template) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing prompt template in sqlite database: {:?}", e)));
             },
-            load_progress_callback
-        ).unwrap_or_else(|err| panic!("Failed to load model: {err}"));
-        self.ai_model = Some(llama);
+        };
         Ok(())
     }
 
-    fn prompt(&self, text: String) -> PyResult<String> {
-        match Database::add_message(&text, false) {
-            Ok(_) => {},
+    // separate (usually smaller) model used only to compute embeddings for long-term memory
+    #[pyo3(signature = (embedding_model_path, use_gpu, architecture=None))]
+    fn load_embedding_model(&mut self, embedding_model_path: &str, use_gpu: bool, architecture: Option<&str>) -> PyResult<()> {
+        self.embedding_model = Some(load_ggml_model(embedding_model_path, use_gpu, architecture, 2048)?);
+        Ok(())
+    }
+
+    #[pyo3(signature = (text, overrides=None))]
+    fn prompt(&mut self, text: String, overrides: Option<&PyDict>) -> PyResult<String> {
+        let sampling = sampling_settings(&self.database, overrides)?;
+        let message_id = match self.database.add_message(&text, false) {
+            Ok(id) => Some(id),
             Err(e) => {
                 eprintln!("Error while adding message to database/short-term memory: {}", e);
+                None
             },
         };
-       match prompt_rs(self, &text) {
-        Ok(v) => Ok(v),
+       match prompt_rs(self, &text, message_id, Reply::New, &sampling, &mut print_token) {
+        Ok((v, report)) => {
+            self.last_context_report = report;
+            Ok(v)
+        },
         Err(e) => Err(pyo3::exceptions::PyValueError::new_err(e))
        }
     }
 
-    fn regenerate_message(&self) -> PyResult<String> {
-        match Database::remove_latest_message() {
-            Ok(_) => {},
+    #[pyo3(signature = (text, callback, overrides=None))]
+    fn prompt_stream(&mut self, py: Python, text: String, callback: PyObject, overrides: Option<&PyDict>) -> PyResult<String> {
+        let sampling = sampling_settings(&self.database, overrides)?;
+        let message_id = match self.database.add_message(&text, false) {
+            Ok(id) => Some(id),
             Err(e) => {
-                let error_msg = format!("Error while removing latest message from sqlite database: {}", e);
-                return Err(pyo3::exceptions::PyValueError::new_err(error_msg));
+                eprintln!("Error while adding message to database/short-term memory: {}", e);
+                None
+            },
+        };
+        let mut callback_error: Option<PyErr> = None;
+        let result = prompt_rs(self, &text, message_id, Reply::New, &sampling, &mut |token: &str| {
+            // the callback can return True to stop the generation early,
+            // if it raises the exception is passed on and the partial reply is not stored
+            match callback.call1(py, (token,)).and_then(|r| r.is_true(py)) {
+                Ok(stop) => Ok(stop),
+                Err(e) => {
+                    callback_error = Some(e);
+                    Err("Error in the callback of prompt_stream".to_string())
+                }
             }
+        });
+        if let Some(e) = callback_error {
+            return Err(e);
         }
-        let previous_prompt = match Database::get_x_msgs(1) {
-            Ok(v) => v,
+        match result {
+            Ok((v, report)) => {
+                self.last_context_report = report;
+                Ok(v)
+            },
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(e))
+        }
+    }
+
+    // generates the reply to the user message again, by default the user message the latest message replies to,
+    // only the messages before the reply are in the prompt and the previous reply is kept as a swipe
+    #[pyo3(signature = (user_message_id=None, overrides=None))]
+    fn regenerate_message(&mut self, user_message_id: Option<u32>, overrides: Option<&PyDict>) -> PyResult<String> {
+        let latest_message = match self.database.get_x_msgs(1) {
+            Ok(messages) => messages.into_iter().next(),
             Err(e) => {
-                let error_msg = format!("Error while fetching previous prompt from sqlite database: {}", e);
-                return Err(pyo3::exceptions::PyValueError::new_err(error_msg));
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting latest message from sqlite database: {}", e)));
+            }
+        };
+        let latest_message = match latest_message {
+            Some(m) if m.ai == "true" => m,
+            Some(_) => {
+                return Err(pyo3::exceptions::PyValueError::new_err("The latest message is not from the ai, send it with prompt() instead of regenerating it"));
+            },
+            None => {
+                return Err(pyo3::exceptions::PyValueError::new_err("There are no messages in the active chat"));
+            }
+        };
+        let reply_id = match user_message_id {
+            None => latest_message.id,
+            Some(id) => {
+                match self.database.get_message(id) {
+                    Ok(Some(m)) if m.ai != "true" => {},
+                    Ok(_) => {
+                        return Err(pyo3::exceptions::PyValueError::new_err(format!("There is no user message with id {} in the active chat", id)));
+                    },
+                    Err(e) => {
+                        return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting message from sqlite database: {}", e)));
+                    }
+                };
+                match self.database.get_next_message(id) {
+                    Ok(Some(m)) => m.id,
+                    Ok(None) => {
+                        return Err(pyo3::exceptions::PyValueError::new_err(format!("User message with id {} has no reply to regenerate", id)));
+                    },
+                    Err(e) => {
+                        return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting reply from sqlite database: {}", e)));
+                    }
+                }
             }
         };
-        let previous_prompt_str = &previous_prompt[0].text;
-        match prompt_rs(self, previous_prompt_str) {
-            Ok(text) => Ok(text),
+        let (user_message, ai_message) = reply_messages(&self.database, Some(reply_id))?;
+        let sampling = sampling_settings(&self.database, overrides)?;
+        match prompt_rs(self, user_message.as_ref().map_or("", |m| m.text.as_str()), user_message.as_ref().map(|m| m.id), Reply::Swipe(ai_message.id), &sampling, &mut print_token) {
+            Ok((text, report)) => {
+                self.last_context_report = report;
+                Ok(text)
+            },
             Err(error) => Err(pyo3::exceptions::PyValueError::new_err(error))
         }
     }
 
-    #[staticmethod]
-    fn clear_messages() -> PyResult<()> {
-        match Database::clear_messages() {
+    // the model keeps writing the latest message, which has to be from the ai,
+    // returns the whole continued message
+    #[pyo3(signature = (overrides=None))]
+    fn continue_message(&mut self, overrides: Option<&PyDict>) -> PyResult<String> {
+        let sampling = sampling_settings(&self.database, overrides)?;
+        let (user_message, ai_message) = reply_messages(&self.database, None)?;
+        match prompt_rs(self, user_message.as_ref().map_or("", |m| m.text.as_str()), user_message.as_ref().map(|m| m.id), Reply::Continue(ai_message.id), &sampling, &mut print_token) {
+            Ok((text, report)) => {
+                self.last_context_report = report;
+                Ok(text)
+            },
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(e))
+        }
+    }
+
+    // generates another reply in place of the ai message, the latest message by default,
+    // the new swipe is selected
+    #[pyo3(signature = (message_id=None, overrides=None))]
+    fn generate_swipe(&mut self, message_id: Option<u32>, overrides: Option<&PyDict>) -> PyResult<String> {
+        let sampling = sampling_settings(&self.database, overrides)?;
+        let (user_message, ai_message) = reply_messages(&self.database, message_id)?;
+        match prompt_rs(self, user_message.as_ref().map_or("", |m| m.text.as_str()), user_message.as_ref().map(|m| m.id), Reply::Swipe(ai_message.id), &sampling, &mut print_token) {
+            Ok((text, report)) => {
+                self.last_context_report = report;
+                Ok(text)
+            },
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(e))
+        }
+    }
+
+    fn list_swipes(&self, message_id: u32) -> PyResult<Vec<Swipe>> {
+        match self.database.get_swipes(message_id) {
+            Ok(swipes) => Ok(swipes),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting swipes from sqlite database: {:?}", e))),
+        }
+    }
+
+    // the selected swipe is used in the prompt history and replaces the long-term memory entry of the message
+    fn select_swipe(&self, message_id: u32, index: usize) -> PyResult<String> {
+        let (user_message, ai_message) = reply_messages(&self.database, Some(message_id))?;
+        let text = match self.database.select_swipe(ai_message.id, index) {
+            Ok(Some(text)) => text,
+            Ok(None) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Message with id {} has no swipe with index {}", message_id, index)));
+            },
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while selecting swipe in sqlite database: {:?}", e)));
+            },
+        };
+        match remember_reply(self, user_message.as_ref().map_or("", |m| m.text.as_str()), user_message.as_ref().map(|m| m.id), &text, ai_message.id, true) {
+            Ok(_) => Ok(text),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(e)),
+        }
+    }
+
+    // the chat starts again with the greeting with the given index or a random one, the first message by default
+    #[pyo3(signature = (greeting=None, random_greeting=false))]
+    fn clear_messages(&self, greeting: Option<usize>, random_greeting: bool) -> PyResult<()> {
+        let greeting = greeting_choice(&self.database, greeting, random_greeting)?;
+        match self.database.clear_messages(greeting) {
             Ok(_) => {},
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while removing messages from sqlite database: {:?}", e)));
@@ -79,20 +224,25 @@ impl Companion {
         Ok(())
     }
 
-    #[staticmethod]
-    fn rm_message(message_id: u32) -> PyResult<()> {
-        match Database::rm_message(message_id) {
+    fn rm_message(&self, message_id: u32) -> PyResult<()> {
+        match self.database.rm_message(message_id) {
             Ok(_) => {},
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while removing message from sqlite database: {:?}", e)));
             },
         };
+        // long-term memory entries created from the message are removed with it
+        match self.memory.delete_message_entries(message_id) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while removing message from long-term memory: {:?}", e)));
+            },
+        };
         Ok(())
     }
 
-    #[staticmethod]
-    fn edit_message(new_text: &str, id: u32) -> PyResult<()> {
-        match Database::modify_message(new_text, id) {
+    fn edit_message(&self, new_text: &str, id: u32) -> PyResult<()> {
+        match self.database.modify_message(new_text, id) {
             Ok(_) => {},
             Err(e) => {
                 let error_msg = format!("Error while removing message from sqlite database: {}", e);
@@ -102,10 +252,9 @@ impl Companion {
         Ok(())
     }
 
-    #[staticmethod]
-    fn fetch_companion_data() -> PyResult<CompanionData> {
+    fn fetch_companion_data(&self) -> PyResult<CompanionData> {
         let companion_data: CompanionData =
-        match Database::get_companion_data() {
+        match self.database.get_companion_data() {
             Ok(c_d) => c_d,
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting companion data from sqlite database: {:?}", e)));
@@ -114,10 +263,9 @@ impl Companion {
         Ok(companion_data)
     }
 
-    #[staticmethod]
-    fn fetch_user_data() -> PyResult<UserData> {
+    fn fetch_user_data(&self) -> PyResult<UserData> {
         let user_data: UserData =
-        match Database::get_user_data() {
+        match self.database.get_user_data() {
             Ok(u_d) => u_d,
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting user data from sqlite database: {:?}", e)));
@@ -126,9 +274,8 @@ impl Companion {
         Ok(user_data)
     }
 
-    #[staticmethod]
-    fn change_first_message(new_first_message: String) -> PyResult<()> {
-        match Database::change_first_message(&new_first_message) {
+    fn change_first_message(&self, new_first_message: String) -> PyResult<()> {
+        match self.database.change_first_message(&new_first_message) {
             Ok(_) => {},
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing companion's first message in sqlite database: {:?}", e)));
@@ -137,9 +284,42 @@ impl Companion {
         Ok(())
     }
 
-    #[staticmethod]
-    fn change_companion_name(new_companion_name: String) -> PyResult<()> {
-        match Database::change_companion_name(&new_companion_name) {
+    // index 0 is the first message, the next ones are the alternate greetings
+    fn list_greetings(&self) -> PyResult<Vec<String>> {
+        match self.database.get_greetings() {
+            Ok(g) => Ok(g),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting greetings from sqlite database: {:?}", e))),
+        }
+    }
+
+    fn add_greeting(&self, text: String) -> PyResult<usize> {
+        match self.database.add_greeting(&text) {
+            Ok(index) => Ok(index),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while adding greeting to sqlite database: {:?}", e))),
+        }
+    }
+
+    fn edit_greeting(&self, index: usize, text: String) -> PyResult<()> {
+        match self.database.change_greeting(index, &text) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(pyo3::exceptions::PyValueError::new_err(format!("There is no greeting with index {}", index))),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing greeting in sqlite database: {:?}", e))),
+        }
+    }
+
+    fn remove_greeting(&self, index: usize) -> PyResult<()> {
+        if index == 0 {
+            return Err(pyo3::exceptions::PyValueError::new_err("The first message can't be removed, change it with edit_greeting() instead"));
+        }
+        match self.database.remove_greeting(index) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(pyo3::exceptions::PyValueError::new_err(format!("There is no greeting with index {}", index))),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while removing greeting from sqlite database: {:?}", e))),
+        }
+    }
+
+    fn change_companion_name(&self, new_companion_name: String) -> PyResult<()> {
+        match self.database.change_companion_name(&new_companion_name) {
             Ok(_) => {},
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing companion name in sqlite database: {:?}", e)));
@@ -148,9 +328,8 @@ impl Companion {
         Ok(())
     }
 
-    #[staticmethod]
-    fn change_user_name(new_user_name: String) -> PyResult<()> {
-        match Database::change_username(&new_user_name) {
+    fn change_user_name(&self, new_user_name: String) -> PyResult<()> {
+        match self.database.change_username(&new_user_name) {
             Ok(_) => {},
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing username in sqlite database: {:?}", e)));
@@ -159,9 +338,8 @@ impl Companion {
         Ok(())
     }
 
-    #[staticmethod]
-    fn change_companion_persona(new_companion_persona: String) -> PyResult<()> {
-        match Database::change_companion_persona(&new_companion_persona) {
+    fn change_companion_persona(&self, new_companion_persona: String) -> PyResult<()> {
+        match self.database.change_companion_persona(&new_companion_persona) {
             Ok(_) => {},
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing companion persona in sqlite database: {:?}", e)));
@@ -170,9 +348,8 @@ impl Companion {
         Ok(())
     }
 
-    #[staticmethod]
-    fn change_companion_example_dialogue(new_example_dialogue: String) -> PyResult<()> {
-        match Database::change_companion_example_dialogue(&new_example_dialogue) {
+    fn change_companion_example_dialogue(&self, new_example_dialogue: String) -> PyResult<()> {
+        match self.database.change_companion_example_dialogue(&new_example_dialogue) {
             Ok(_) => {},
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing companion example dialogue in sqlite database: {:?}", e)));
@@ -181,9 +358,8 @@ impl Companion {
         Ok(())
     }
 
-    #[staticmethod]
-    fn change_user_persona(new_user_persona: String) -> PyResult<()> {
-        match Database::change_user_persona(&new_user_persona) {
+    fn change_user_persona(&self, new_user_persona: String) -> PyResult<()> {
+        match self.database.change_user_persona(&new_user_persona) {
             Ok(_) => {},
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing user persona in sqlite database: {:?}", e)));
@@ -192,9 +368,10 @@ impl Companion {
         Ok(())
     }
 
-    #[staticmethod]
-    fn change_companion_data(new_companion_name: String, new_companion_persona: String, new_example_dialogue: String, new_first_message: String, long_term_memory_limit: u32, short_term_memory_limit: u32, roleplay: bool) -> PyResult<()> {
-        match Database::change_companion(&new_companion_name, &new_companion_persona, &new_example_dialogue, &new_first_message, long_term_memory_limit, short_term_memory_limit, roleplay) {
+    // the python api takes every field as a separate argument
+    #[allow(clippy::too_many_arguments)]
+    fn change_companion_data(&self, new_companion_name: String, new_companion_persona: String, new_example_dialogue: String, new_first_message: String, long_term_memory_limit: u32, short_term_memory_limit: u32, roleplay: bool) -> PyResult<()> {
+        match self.database.change_companion(&new_companion_name, &new_companion_persona, &new_example_dialogue, &new_first_message, long_term_memory_limit, short_term_memory_limit, roleplay) {
             Ok(_) => {},
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing companion data in sqlite database: {:?}", e)));
@@ -203,9 +380,8 @@ impl Companion {
         Ok(())
     }
 
-    #[staticmethod]
-    fn change_user_data(new_user_name: String, new_user_persona: String) -> PyResult<()> {
-        match Database::change_user(&new_user_name, &new_user_persona) {
+    fn change_user_data(&self, new_user_name: String, new_user_persona: String) -> PyResult<()> {
+        match self.database.change_user(&new_user_name, &new_user_persona) {
             Ok(_) => {},
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing user data in sqlite database: {:?}", e)));
@@ -214,17 +390,10 @@ impl Companion {
         Ok(())
     }
 
-    #[staticmethod]
-    fn add_custom_data(text: String) -> PyResult<()> {
-        match VectorDatabase::connect() {
-            Ok(vdb) => {
-                match vdb.add_entry(&(text+"\n")) {
-                    Ok(_) => {},
-                    Err(e) => {
-                        return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while adding custom data to long-term memory: {:?}", e)));
-                    },
-                };
-            },
+    fn add_custom_data(&self, text: String) -> PyResult<()> {
+        let entry = text + "\n";
+        match self.memory.add_entry(&self.memory_entry(&entry, "custom", Vec::new()), self.embed_for_memory(&entry).as_deref()) {
+            Ok(_) => {},
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while adding custom data to long-term memory: {:?}", e)));
             },
@@ -232,17 +401,9 @@ impl Companion {
         Ok(())
     }
 
-    #[staticmethod]
-    fn erase_longterm_mem() -> PyResult<()> {
-        match VectorDatabase::connect() {
-            Ok(vdb) => {
-                match vdb.erase_memory() {
-                    Ok(_) => {},
-                    Err(e) => {
-                        return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while erasing data from long-term memory: {:?}", e)));
-                    },
-                };
-            },
+    fn erase_longterm_mem(&self) -> PyResult<()> {
+        match self.memory.erase_memory() {
+            Ok(_) => {},
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while erasing data from long-term memory: {:?}", e)));
             },
@@ -250,9 +411,56 @@ impl Companion {
         Ok(())
     }
 
-    #[staticmethod]
-    fn change_longterm_memory_limit(new_limit: u32) -> PyResult<()> {
-        match Database::change_long_term_memory(new_limit) {
+    // merges the segments of the index into one and removes files of deleted entries
+    fn optimize_memory(&self) -> PyResult<()> {
+        match self.memory.optimize() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while optimizing long-term memory: {:?}", e))),
+        }
+    }
+
+    // entries ordered from the oldest
+    #[pyo3(signature = (offset=0, limit=20))]
+    fn list_memories(&self, offset: usize, limit: usize) -> PyResult<Vec<MemoryEntry>> {
+        match self.memory.list_entries(offset, limit) {
+            Ok(entries) => Ok(entries),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while listing long-term memory entries: {:?}", e))),
+        }
+    }
+
+    fn count_memories(&self) -> PyResult<u64> {
+        match self.memory.count_entries() {
+            Ok(count) => Ok(count),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while counting long-term memory entries: {:?}", e))),
+        }
+    }
+
+    fn get_memory(&self, memory_id: u64) -> PyResult<MemoryEntry> {
+        match self.memory.get_entry(memory_id) {
+            Ok(Some(entry)) => Ok(entry),
+            Ok(None) => Err(pyo3::exceptions::PyValueError::new_err(format!("There is no long-term memory entry with id {}", memory_id))),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting long-term memory entry: {:?}", e))),
+        }
+    }
+
+    fn edit_memory(&self, memory_id: u64, text: String) -> PyResult<()> {
+        match self.memory.edit_entry(memory_id, &text, self.embed_for_memory(&text).as_deref()) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(pyo3::exceptions::PyValueError::new_err(format!("There is no long-term memory entry with id {}", memory_id))),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while editing long-term memory entry: {:?}", e))),
+        }
+    }
+
+    fn delete_memory(&self, memory_id: u64) -> PyResult<()> {
+        match self.memory.delete_entry(memory_id) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(pyo3::exceptions::PyValueError::new_err(format!("There is no long-term memory entry with id {}", memory_id))),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while removing long-term memory entry: {:?}", e))),
+        }
+    }
+
+    fn change_longterm_memory_limit(&self, new_limit: u32) -> PyResult<()> {
+        match self.database.change_long_term_memory(new_limit) {
             Ok(_) => {},
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing long-term memory limit in sqlite database: {:?}", e)));
@@ -261,9 +469,8 @@ impl Companion {
         Ok(())
     }
 
-    #[staticmethod]
-    fn change_shortterm_memory_limit(new_limit: u32) -> PyResult<()> {
-        match Database::change_short_term_memory(new_limit) {
+    fn change_shortterm_memory_limit(&self, new_limit: u32) -> PyResult<()> {
+        match self.database.change_short_term_memory(new_limit) {
             Ok(_) => {},
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing short-term memory limit in sqlite database: {:?}", e)));
@@ -272,111 +479,594 @@ impl Companion {
         Ok(())
     }
 
-    #[staticmethod]
-    fn change_roleplay(enable: bool) -> PyResult<()> {
-        match Database::disable_enable_roleplay(enable) {
+    fn create_companion(&self, name: String, persona: String, example_dialogue: String, first_message: String) -> PyResult<u32> {
+        match self.database.create_companion(&name, &persona, &example_dialogue, &first_message) {
+            Ok(id) => Ok(id),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while creating companion in sqlite database: {:?}", e))),
+        }
+    }
+
+    fn list_companions(&self) -> PyResult<Vec<CompanionData>> {
+        match self.database.list_companions() {
+            Ok(c) => Ok(c),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting companions from sqlite database: {:?}", e))),
+        }
+    }
+
+    fn active_companion_id(&self) -> u32 {
+        self.database.companion_id
+    }
+
+    fn select_companion(&mut self, companion_id: u32) -> PyResult<()> {
+        if companion_id == self.database.companion_id {
+            return Ok(());
+        }
+        match self.database.companion_exists(companion_id) {
+            Ok(true) => {},
+            Ok(false) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("There is no companion with id {}", companion_id)));
+            },
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while selecting companion with id {}: {:?}", companion_id, e)));
+            },
+        };
+        // the long-term memory of the new companion is opened first, so a failure leaves the previous companion active
+        let memory_path = self.memory_path_for(companion_id);
+        let memory = match VectorDatabase::connect(&memory_path) {
+            Ok(vdb) => vdb,
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while connecting to long-term memory (tantivy) in {}: {}", memory_path.display(), e)));
+            }
+        };
+        match self.database.select_companion(companion_id) {
             Ok(_) => {},
             Err(e) => {
-                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while enabling/disabling roleplay in sqlite database: {:?}", e)));
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while selecting companion with id {}: {:?}", companion_id, e)));
             },
         };
+        // the previous companion's pending memories are committed when its index is dropped
+        self.memory = memory;
         Ok(())
     }
 
-    #[staticmethod]
-    fn import_character_json(character_json_text: String) -> PyResult<()> {
-        let character_json: CharacterJson = match serde_json::from_str(&character_json_text) {
-            Ok(v) => v,
+    fn delete_companion(&self, companion_id: u32) -> PyResult<()> {
+        if companion_id == self.database.companion_id {
+            return Err(pyo3::exceptions::PyValueError::new_err("Can't delete the active companion, select another companion first"));
+        }
+        match self.database.delete_companion(companion_id) {
+            Ok(_) => {},
             Err(e) => {
-                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while parsing provided text as json: {:?}", e)));
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while deleting companion from sqlite database: {:?}", e)));
+            },
+        };
+        let companion_memory = self.memory_path_for(companion_id);
+        if companion_memory.exists() {
+            match fs::remove_dir_all(&companion_memory) {
+                Ok(_) => {},
+                Err(e) => {
+                    return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while deleting companion's long-term memory: {:?}", e)));
+                },
+            };
+        }
+        Ok(())
+    }
+
+    fn create_user(&self, name: String, persona: String) -> PyResult<u32> {
+        match self.database.create_user(&name, &persona) {
+            Ok(id) => Ok(id),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while creating user in sqlite database: {:?}", e))),
+        }
+    }
+
+    fn list_users(&self) -> PyResult<Vec<UserData>> {
+        match self.database.list_users() {
+            Ok(u) => Ok(u),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting users from sqlite database: {:?}", e))),
+        }
+    }
+
+    fn active_user_id(&self) -> u32 {
+        self.database.user_id
+    }
+
+    fn select_user(&mut self, user_id: u32) -> PyResult<()> {
+        match self.database.select_user(user_id) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while selecting user with id {}: {:?}", user_id, e)));
+            },
+        };
+        Ok(())
+    }
+
+    fn delete_user(&self, user_id: u32) -> PyResult<()> {
+        if user_id == self.database.user_id {
+            return Err(pyo3::exceptions::PyValueError::new_err("Can't delete the active user, select another user first"));
+        }
+        match self.database.delete_user(user_id) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while deleting user from sqlite database: {:?}", e)));
+            },
+        };
+        Ok(())
+    }
+
+    #[pyo3(signature = (name="Chat", greeting=None, random_greeting=false))]
+    fn create_chat(&mut self, name: &str, greeting: Option<usize>, random_greeting: bool) -> PyResult<u32> {
+        let greeting = greeting_choice(&self.database, greeting, random_greeting)?;
+        match self.database.create_chat(name, greeting) {
+            Ok(id) => Ok(id),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while creating chat in sqlite database: {:?}", e))),
+        }
+    }
+
+    fn list_chats(&self) -> PyResult<Vec<ChatData>> {
+        match self.database.list_chats() {
+            Ok(c) => Ok(c),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting chats from sqlite database: {:?}", e))),
+        }
+    }
+
+    fn active_chat_id(&self) -> u32 {
+        self.database.chat_id
+    }
+
+    fn select_chat(&mut self, chat_id: u32) -> PyResult<()> {
+        match self.database.select_chat(chat_id) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while selecting chat with id {}: {:?}", chat_id, e)));
+            },
+        };
+        Ok(())
+    }
+
+    fn rename_chat(&self, chat_id: u32, new_name: &str) -> PyResult<()> {
+        match self.database.rename_chat(chat_id, new_name) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while renaming chat in sqlite database: {:?}", e)));
+            },
+        };
+        Ok(())
+    }
+
+    fn delete_chat(&self, chat_id: u32) -> PyResult<()> {
+        if chat_id == self.database.chat_id {
+            return Err(pyo3::exceptions::PyValueError::new_err("Can't delete the active chat, select or create another chat first"));
+        }
+        match self.database.delete_chat(chat_id) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while deleting chat from sqlite database: {:?}", e)));
+            },
+        };
+        Ok(())
+    }
+
+    fn fetch_memory_settings(&self) -> PyResult<MemorySettings> {
+        match self.database.get_memory_settings() {
+            Ok(s) => Ok(s),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting memory settings from sqlite database: {:?}", e))),
+        }
+    }
+
+    // bm25 (keyword search), vector (embedding similarity) or hybrid (both),
+    // entries added while bm25 was selected don't have embeddings and are only found by keyword search
+    fn change_memory_retrieval(&self, retrieval: &str) -> PyResult<()> {
+        if !RETRIEVAL_MODES.contains(&retrieval) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!("Unknown retrieval mode '{}', supported modes are: {}", retrieval, RETRIEVAL_MODES.join(", "))));
+        }
+        let mut settings = match self.database.get_memory_settings() {
+            Ok(s) => s,
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting memory settings from sqlite database: {:?}", e)));
             }
         };
-        match Database::import_companion(&character_json.name, &character_json.description, &character_json.mes_example, &character_json.first_mes) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while importing character via character class to sqlite database {:?}", e))),
+        settings.retrieval = retrieval.to_string();
+        match self.database.change_memory_settings(&settings) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing memory settings in sqlite database: {:?}", e)));
+            },
+        };
+        Ok(())
+    }
+
+    // how keyword and vector results are combined in hybrid retrieval, rrf (reciprocal rank fusion) or weighted
+    #[pyo3(signature = (fusion, keyword_weight=1.0, vector_weight=1.0))]
+    fn change_memory_fusion(&self, fusion: &str, keyword_weight: f32, vector_weight: f32) -> PyResult<()> {
+        if !FUSION_METHODS.contains(&fusion) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!("Unknown fusion method '{}', supported methods are: {}", fusion, FUSION_METHODS.join(", "))));
         }
+        if keyword_weight < 0.0 || vector_weight < 0.0 {
+            return Err(pyo3::exceptions::PyValueError::new_err("Fusion weights can't be negative"));
+        }
+        let mut settings = match self.database.get_memory_settings() {
+            Ok(s) => s,
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting memory settings from sqlite database: {:?}", e)));
+            }
+        };
+        settings.fusion = fusion.to_string();
+        settings.keyword_weight = keyword_weight;
+        settings.vector_weight = vector_weight;
+        match self.database.change_memory_settings(&settings) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing memory settings in sqlite database: {:?}", e)));
+            },
+        };
+        Ok(())
     }
 
-    #[staticmethod]
-    fn import_character_card(character_card_path: &str) -> PyResult<()> {
-        let decoder = png::Decoder::new(File::open(character_card_path)?);
-        let reader = decoder.read_info().unwrap();
-        let character_base64_option: Option<String> = reader.info().uncompressed_latin1_text.iter()
-            .filter(|text_chunk| text_chunk.keyword == "chara")
-            .map(|text_chunk| text_chunk.text.clone())
-            .next();
-            let character_base64: String = match character_base64_option {
-                Some(v) => v,
-                None => {
-                    let mut f_buffer = Vec::new();
-                    File::open(character_card_path)?.read_to_end(&mut f_buffer)?;
-                    let text_chunk_start = f_buffer.windows(9).position(|window| window == b"tEXtchara").ok_or_else(|| pyo3::exceptions::PyValueError::new_err("No tEXt chunk with name 'chara' found"))?;
-                    let text_chunk_end = f_buffer.windows(4).rposition(|window| window == b"IEND").ok_or_else(|| pyo3::exceptions::PyValueError::new_err("No tEXt chunk with name 'chara' found"))?;
-                    String::from_utf8_lossy(&f_buffer[text_chunk_start + 10..text_chunk_end - 8]).to_string()
-                }
-            };
-        let engine = GeneralPurpose::new(&STANDARD, GeneralPurposeConfig::new());
-        let character_bytes = match engine.decode(character_base64) {
-            Ok(b) => b,
+    // scores of every retrieval mode are between 0 and 1, a keyword match with bm25 score 4 scores 0.5
+    fn change_memory_min_score(&self, min_score: f32) -> PyResult<()> {
+        if !(0.0..=1.0).contains(&min_score) {
+            return Err(pyo3::exceptions::PyValueError::new_err("Minimum score has to be between 0 and 1"));
+        }
+        let mut settings = match self.database.get_memory_settings() {
+            Ok(s) => s,
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting memory settings from sqlite database: {:?}", e)));
+            }
+        };
+        settings.min_score = min_score;
+        match self.database.change_memory_settings(&settings) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing memory settings in sqlite database: {:?}", e)));
+            },
+        };
+        Ok(())
+    }
+
+    // summarize messages that left short-term memory once there are at least summarize_after of them, 0 disables it
+    fn change_memory_summarization(&self, summarize_after: u32) -> PyResult<()> {
+        let mut settings = match self.database.get_memory_settings() {
+            Ok(s) => s,
             Err(e) => {
-                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while decoding base64 character data from character card: {:?}", e)));
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting memory settings from sqlite database: {:?}", e)));
             }
         };
-        let character_text: &str = match std::str::from_utf8(&character_bytes) {
+        settings.summarize_after = summarize_after;
+        match self.database.change_memory_settings(&settings) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing memory settings in sqlite database: {:?}", e)));
+            },
+        };
+        Ok(())
+    }
+
+    fn fetch_summary(&self) -> PyResult<Option<Summary>> {
+        match self.database.get_latest_summary() {
+            Ok(s) => Ok(s),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting summary from sqlite database: {:?}", e))),
+        }
+    }
+
+    // returns (score, entry) pairs scoring at least min_score of the memory settings,
+    // since and until are unix timestamps, sort_by is relevance, newest or oldest
+    #[pyo3(signature = (query, limit=10, source=None, chat_id=None, user_id=None, since=None, until=None, sort_by="relevance"))]
+    fn search_memory(&self, query: &str, limit: usize, source: Option<String>, chat_id: Option<u32>, user_id: Option<u32>, since: Option<i64>, until: Option<i64>, sort_by: &str) -> PyResult<Vec<(f32, MemoryEntry)>> {
+        if let Some(source) = &source {
+            if !MEMORY_SOURCES.contains(&source.as_str()) {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Unknown memory source '{}', supported sources are: {}", source, MEMORY_SOURCES.join(", "))));
+            }
+        }
+        if !MEMORY_SORTING.contains(&sort_by) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!("Unknown sorting '{}', supported sorting is: {}", sort_by, MEMORY_SORTING.join(", "))));
+        }
+        let settings = match self.database.get_memory_settings() {
             Ok(s) => s,
             Err(e) => {
-                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while parsing decoded base64 bytes to utf8 string: {:?}", e)));
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting memory settings from sqlite database: {:?}", e)));
+            }
+        };
+        let filter = MemoryFilter { source, chat_id, user_id, since, until, exclude_message_id: None };
+        match self.memory.get_matches(query, self.embed_for_memory(query).as_deref(), limit, &settings, &filter, sort_by) {
+            Ok(entries) => Ok(entries),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while searching long-term memory: {:?}", e))),
+        }
+    }
+
+    fn fetch_sampling_settings(&self) -> PyResult<SamplingSettings> {
+        match self.database.get_sampling_settings() {
+            Ok(s) => Ok(s),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting sampling settings from sqlite database: {:?}", e))),
+        }
+    }
+
+    #[pyo3(signature = (**settings))]
+    fn change_sampling_settings(&self, settings: Option<&PyDict>) -> PyResult<()> {
+        let new_settings = sampling_settings(&self.database, settings)?;
+        match self.database.change_sampling_settings(&new_settings) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing sampling settings in sqlite database: {:?}", e)));
+            },
+        };
+        Ok(())
+    }
+
+    fn last_context_report(&self) -> ContextReport {
+        self.last_context_report.clone()
+    }
+
+    fn fetch_context_settings(&self) -> PyResult<ContextSettings> {
+        match self.database.get_context_settings() {
+            Ok(s) => Ok(s),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting context settings from sqlite database: {:?}", e))),
+        }
+    }
+
+    // context_length is applied the next time a model is loaded, lorebook_budget is kept if not given
+    #[pyo3(signature = (context_length, response_reserve, lorebook_budget=None))]
+    fn change_context_settings(&self, context_length: usize, response_reserve: usize, lorebook_budget: Option<usize>) -> PyResult<()> {
+        if response_reserve >= context_length {
+            return Err(pyo3::exceptions::PyValueError::new_err("Response reserve has to be smaller than the context length"));
+        }
+        let lorebook_budget = match lorebook_budget {
+            Some(budget) => budget,
+            None => match self.database.get_context_settings() {
+                Ok(s) => s.lorebook_budget,
+                Err(e) => {
+                    return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting context settings from sqlite database: {:?}", e)));
+                }
+            },
+        };
+        match self.database.change_context_settings(&ContextSettings { context_length, response_reserve, lorebook_budget }) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing context settings in sqlite database: {:?}", e)));
+            },
+        };
+        Ok(())
+    }
+
+    #[staticmethod]
+    fn list_prompt_templates() -> Vec<&'static str> {
+        TEMPLATE_NAMES.to_vec()
+    }
+
+    fn fetch_prompt_template(&self) -> PyResult<PromptTemplate> {
+        match self.database.get_prompt_template() {
+            Ok(t) => Ok(t),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting prompt template from sqlite database: {:?}", e))),
+        }
+    }
+
+    fn change_prompt_template(&self, template_name: &str) -> PyResult<()> {
+        let template = match PromptTemplate::builtin(template_name) {
+            Some(t) => t,
+            None => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Unknown prompt template '{}', available templates are: {} (use set_custom_prompt_template for custom templates)", template_name, TEMPLATE_NAMES.join(", "))));
             }
         };
-        let character_data: CharacterCard = serde_json::from_str(character_text).expect("Your image file does not contain correct json data");
-        match Database::import_companion(&character_data.name, &character_data.description, &character_data.mes_example, &character_data.first_mes) {
+        match self.database.change_prompt_template(&template) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing prompt template in sqlite database: {:?}", e)));
+            },
+        };
+        Ok(())
+    }
+
+    fn set_custom_prompt_template(&self, context: &str, user_message: &str, ai_message: &str, stop_sequences: Vec<String>) -> PyResult<()> {
+        let template = match PromptTemplate::custom(context, user_message, ai_message, stop_sequences) {
+            Ok(t) => t,
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(e));
+            }
+        };
+        match self.database.change_prompt_template(&template) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing prompt template in sqlite database: {:?}", e)));
+            },
+        };
+        Ok(())
+    }
+
+    fn change_roleplay(&self, enable: bool) -> PyResult<()> {
+        match self.database.disable_enable_roleplay(enable) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while enabling/disabling roleplay in sqlite database: {:?}", e)));
+            },
+        };
+        Ok(())
+    }
+
+    // accepts V1 character json and chara_card_v2/chara_card_v3 json with the nested data object
+    fn import_character_json(&self, character_json_text: String) -> PyResult<()> {
+        let character_data = match parse_character_json(&character_json_text) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(e));
+            }
+        };
+        match self.database.import_companion(&character_data) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while importing character via character class to sqlite database {:?}", e)));
+            }
+        };
+        import_character_book(&self.database, character_data.character_book.as_ref())
+    }
+
+    fn import_character_card(&self, character_card_path: &str) -> PyResult<()> {
+        let mut f_buffer = Vec::new();
+        File::open(character_card_path)?.read_to_end(&mut f_buffer)?;
+        let character_data = match read_character_card(&f_buffer) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(e));
+            }
+        };
+        match self.database.import_companion(&character_data) {
             Ok(_) => {},
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while importing companion data via character card: {:?}", e)));
             }
         };
+        import_character_book(&self.database, character_data.character_book.as_ref())
+    }
+
+    // writes a V2 character card, the image is the avatar (has to be png) or a generated placeholder
+    #[pyo3(signature = (path, avatar_png_path=None))]
+    fn export_character_card(&self, path: &str, avatar_png_path: Option<&str>) -> PyResult<()> {
+        let companion_data = match self.database.get_companion_data() {
+            Ok(c) => c,
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting companion data from sqlite database: {:?}", e)));
+            }
+        };
+        let avatar = match avatar_png_path {
+            Some(avatar_path) => Some(fs::read(avatar_path)?),
+            None => None,
+        };
+        let lorebook = match self.database.get_lorebook_entries() {
+            Ok(entries) => entries,
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting lorebook entries from sqlite database: {:?}", e)));
+            }
+        };
+        let context_settings = match self.database.get_context_settings() {
+            Ok(s) => s,
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting context settings from sqlite database: {:?}", e)));
+            }
+        };
+        let mut card = card_from_companion(&companion_data);
+        card.character_book = match lorebook.is_empty() {
+            true => None,
+            false => Some(character_book_from_entries(&companion_data.name, &lorebook, context_settings.lorebook_budget)),
+        };
+        let card = match write_character_card(&card, avatar.as_deref()) {
+            Ok(c) => c,
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(e));
+            }
+        };
+        fs::write(path, card)?;
         Ok(())
     }
 
-    #[staticmethod]
-    fn import_messages_json(messages_json_text: String) -> PyResult<()> {
-        let messages_json: MessagesJson = match serde_json::from_str(&messages_json_text) {
+    fn list_lorebook_entries(&self) -> PyResult<Vec<LorebookEntry>> {
+        match self.database.get_lorebook_entries() {
+            Ok(entries) => Ok(entries),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting lorebook entries from sqlite database: {:?}", e))),
+        }
+    }
+
+    // fields: name, secondary_keys, insertion_order, position, scan_depth, enabled, constant, case_sensitive
+    #[pyo3(signature = (keys, content, **fields))]
+    fn add_lorebook_entry(&self, keys: Vec<String>, content: String, fields: Option<&PyDict>) -> PyResult<u32> {
+        let entry = lorebook_entry(LorebookEntry { keys, content, ..Default::default() }, fields)?;
+        match self.database.add_lorebook_entry(&entry) {
+            Ok(id) => Ok(id),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while adding lorebook entry to sqlite database: {:?}", e))),
+        }
+    }
+
+    // only the given fields are changed, keys and content can be changed too
+    #[pyo3(signature = (entry_id, **fields))]
+    fn edit_lorebook_entry(&self, entry_id: u32, fields: Option<&PyDict>) -> PyResult<()> {
+        let entries = match self.database.get_lorebook_entries() {
+            Ok(entries) => entries,
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting lorebook entries from sqlite database: {:?}", e)));
+            }
+        };
+        let entry = match entries.into_iter().find(|entry| entry.id == entry_id) {
+            Some(entry) => lorebook_entry(entry, fields)?,
+            None => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("There is no lorebook entry with id {}", entry_id)));
+            }
+        };
+        match self.database.change_lorebook_entry(&entry) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing lorebook entry in sqlite database: {:?}", e))),
+        }
+    }
+
+    fn remove_lorebook_entry(&self, entry_id: u32) -> PyResult<()> {
+        match self.database.remove_lorebook_entry(entry_id) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(pyo3::exceptions::PyValueError::new_err(format!("There is no lorebook entry with id {}", entry_id))),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while removing lorebook entry from sqlite database: {:?}", e))),
+        }
+    }
+
+    // accepts a character_book object or a character card json containing one, the entries are added to the existing ones
+    fn import_lorebook_json(&self, lorebook_json_text: String) -> PyResult<Vec<u32>> {
+        let value: serde_json::Value = match serde_json::from_str(&lorebook_json_text) {
             Ok(v) => v,
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while parsing provided text as json: {:?}", e)));
             }
         };
-        let mut messages_iter = messages_json.messages.iter();
-        for message in messages_iter.to_owned() {
-            match Database::add_message(&message.text, message.ai) {
-                Ok(_) => {},
-                Err(e) => {
-                    return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while adding message to database/short-term memory: {:?}", e)));
-                },
-            };
+        let book = value.pointer("/data/character_book").or_else(|| value.get("character_book")).unwrap_or(&value);
+        let (entries, _) = entries_from_character_book(book);
+        match self.database.add_lorebook_entries(&entries, false) {
+            Ok(ids) => Ok(ids),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while adding lorebook entries to sqlite database: {:?}", e))),
         }
-        let vector = match VectorDatabase::connect() {
-            Ok(vd) => vd,
+    }
+
+    // lorebook in the character_book format of character cards
+    fn export_lorebook_json(&self) -> PyResult<String> {
+        let entries = match self.database.get_lorebook_entries() {
+            Ok(entries) => entries,
             Err(e) => {
-                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while connecting to tantivy: {:?}", e)));
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting lorebook entries from sqlite database: {:?}", e)));
             }
         };
-        while let Some(msg1) = messages_iter.next() {
-            if let Some(msg2) = messages_iter.next() {
-                match vector.add_entry(&format!("{}: {}\n{}: {}\n", if msg1.ai {"{{char}}"} else {"{{user}}"}, msg1.text, if msg2.ai {"{{char}}"} else {"{{user}}"}, msg2.text)) {
-                    Ok(_) => {},
-                    Err(e) => {
-                        return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while importing message to long-term memory: {:?}", e)));
-                    },
-                };
+        let (companion_data, context_settings) = match (self.database.get_companion_data(), self.database.get_context_settings()) {
+            (Ok(c), Ok(s)) => (c, s),
+            (Err(e), _) | (_, Err(e)) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting companion data from sqlite database: {:?}", e)));
             }
+        };
+        match serde_json::to_string_pretty(&character_book_from_entries(&companion_data.name, &entries, context_settings.lorebook_budget)) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while encoding lorebook as json: {:?}", e))),
         }
-        Ok(())
     }
 
-    #[staticmethod]
-    fn get_messages_json() -> PyResult<String> {
-        let database_messages = match Database::get_messages() {
+    fn import_messages_json(&self, messages_json_text: String) -> PyResult<()> {
+        let messages_json: MessagesJson = match serde_json::from_str(&messages_json_text) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while parsing provided text as json: {:?}", e)));
+            }
+        };
+        let messages: Vec<(&str, bool)> = messages_json.messages.iter().map(|message| (message.text.as_str(), message.ai)).collect();
+        let message_ids = match self.database.add_messages(&messages) {
+            Ok(ids) => ids,
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while adding messages to database/short-term memory: {:?}", e)));
+            },
+        };
+        let mut entries: Vec<(MemoryEntry, Option<Vec<f32>>)> = Vec::new();
+        let mut messages_iter = messages_json.messages.iter().zip(message_ids);
+        while let Some((msg1, id1)) = messages_iter.next() {
+            if let Some((msg2, id2)) = messages_iter.next() {
+                let entry = format!("{}: {}\n{}: {}\n", if msg1.ai {"{{char}}"} else {"{{user}}"}, msg1.text, if msg2.ai {"{{char}}"} else {"{{user}}"}, msg2.text);
+                entries.push((self.memory_entry(&entry, "imported", vec![id1, id2]), self.embed_for_memory(&entry)));
+            }
+        }
+        match self.memory.add_entries(&entries) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Error while importing messages to long-term memory: {:?}", e))),
+        }
+    }
+
+    fn get_messages_json(&self) -> PyResult<String> {
+        let database_messages = match self.database.get_messages() {
             Ok(m) => m,
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while fetching messages from json text: {:?}", e)));
@@ -401,9 +1091,8 @@ impl Companion {
         Ok(json_messages)
     }
 
-    #[staticmethod]
-    fn get_character_json() -> PyResult<String> {
-        let companion_data = match Database::get_companion_data() {
+    fn get_character_json(&self) -> PyResult<String> {
+        let companion_data = match self.database.get_companion_data() {
             Ok(m) => m,
             Err(e) => {
                 return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while fetching companion data as json: {:?}", e)));
@@ -412,6 +1101,8 @@ impl Companion {
         let character_data: CharacterJson = CharacterJson {
             name: companion_data.name,
             description: companion_data.persona,
+            personality: companion_data.personality,
+            scenario: companion_data.scenario,
             first_mes: companion_data.first_message,
             mes_example: companion_data.example_dialogue,
         };
@@ -423,45 +1114,269 @@ impl Companion {
 }
 
 
+// data_dir defaults to the current directory, database_path and memory_path default to
+// companion.db and longterm_mem inside of data_dir
 #[pyfunction]
-fn init() -> PyResult<Companion> {
-    match Database::create() {
+#[pyo3(signature = (data_dir=None, database_path=None, memory_path=None))]
+fn init(data_dir: Option<&str>, database_path: Option<&str>, memory_path: Option<&str>) -> PyResult<Companion> {
+    let data_dir = Path::new(data_dir.unwrap_or("."));
+    if data_dir.is_file() {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!("Data directory {} is a file", data_dir.display())));
+    }
+    match fs::create_dir_all(data_dir) {
+        Ok(_) => {},
+        Err(e) => {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while creating data directory {}: {}", data_dir.display(), e)));
+        }
+    }
+    let database_path: PathBuf = database_path.map(PathBuf::from).unwrap_or_else(|| data_dir.join("companion.db"));
+    let memory_path: PathBuf = memory_path.map(PathBuf::from).unwrap_or_else(|| data_dir.join("longterm_mem"));
+
+    let mut database = match Database::new(&database_path) {
+        Ok(d) => d,
+        Err(e) => {
+            let error_msg = format!("Error while connecting to sqlite database {}: {}", database_path.display(), e);
+            return Err(pyo3::exceptions::PyValueError::new_err(error_msg)); }
+    };
+    match database.create() {
         Ok(_) => {},
         
         Err(e) => {
-            let error_msg = format!("Error while connecting to sqlite database:, {}", e);
+            let error_msg = format!("Error while connecting to sqlite database {}: {}", database_path.display(), e);
             return Err(pyo3::exceptions::PyValueError::new_err(error_msg)); }
     }
 
-    match VectorDatabase::connect() {
-        Ok(_) => { }
-        Err(e) => { 
-            let error_msg = format!("Error while connecting to long-term memory (tantivy): {}", e);
+    let companion_memory_path = memory_path.join(format!("companion_{}", database.companion_id));
+    match VectorDatabase::migrate_single_index(&memory_path, &companion_memory_path) {
+        Ok(_) => {},
+        Err(e) => {
+            let error_msg = format!("Error while moving long-term memory (tantivy) to companion directory: {}", e);
             return Err(pyo3::exceptions::PyValueError::new_err(error_msg)); }
     }
 
+    let memory = match VectorDatabase::connect(&companion_memory_path) {
+        Ok(vdb) => vdb,
+        Err(e) => { 
+            let error_msg = format!("Error while connecting to long-term memory (tantivy) in {}: {}", companion_memory_path.display(), e);
+            return Err(pyo3::exceptions::PyValueError::new_err(error_msg)); }
+    };
+
     Ok(Companion {
         ai_model: None,
-        is_llama2: false,
+        embedding_model: None,
+        database,
+        memory_path,
+        memory,
+        last_context_report: Default::default(),
     })
 }
 
 fn load_progress_callback(_: LoadProgress) {}
 
+fn load_ggml_model(model_path: &str, use_gpu: bool, architecture: Option<&str>, context_size: usize) -> PyResult<Box<dyn llm::Model>> {
+    if !model_path.ends_with(".bin") {
+        return Err(pyo3::exceptions::PyValueError::new_err("Error while loading ai model, make sure that the path to the ai model is correct, that it is a valid GGML model and that the file has a .bin extension"));
+    }
+    let model_architecture = match architecture {
+        Some(name) => match model_architecture(name) {
+            Some(arch) => arch,
+            None => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Unknown model architecture '{}', supported architectures are: {}", name, SUPPORTED_ARCHITECTURES.join(", "))));
+            }
+        },
+        None => guess_model_architecture(model_path),
+    };
+    match llm::load_dynamic(
+        Some(model_architecture),
+        std::path::Path::new(model_path),
+        llm::TokenizerSource::Embedded,
+        llm::ModelParameters {
+            prefer_mmap: true,
+            context_size,
+            use_gpu: use_gpu,
+            ..Default::default()
+        },
+        load_progress_callback
+    ) {
+        Ok(m) => Ok(m),
+        Err(e) => Err(pyo3::exceptions::PyValueError::new_err(format!("Failed to load model: {}", e))),
+    }
+}
+
+const SUPPORTED_ARCHITECTURES: [&str; 7] = ["llama", "gptneox", "mpt", "falcon", "gptj", "gpt2", "bloom"];
+
+fn model_architecture(name: &str) -> Option<llm::ModelArchitecture> {
+    match name.to_lowercase().replace(['-', '_'], "").as_str() {
+        "llama" => Some(llm::ModelArchitecture::Llama),
+        "gptneox" => Some(llm::ModelArchitecture::GptNeoX),
+        "mpt" => Some(llm::ModelArchitecture::Mpt),
+        "falcon" => Some(llm::ModelArchitecture::Falcon),
+        "gptj" => Some(llm::ModelArchitecture::GptJ),
+        "gpt2" => Some(llm::ModelArchitecture::Gpt2),
+        "bloom" => Some(llm::ModelArchitecture::Bloom),
+        _ => None,
+    }
+}
+
+// GGML files do not store their architecture, so when it is not given explicitly look for a known
+// architecture name among the words of the file name ("gpt-neox" is two of them), llama otherwise like before
+fn guess_model_architecture(ai_model_path: &str) -> llm::ModelArchitecture {
+    let file_name = std::path::Path::new(ai_model_path)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let words: Vec<&str> = file_name.split(['-', '_', '.']).collect();
+    words.iter().map(|word| word.to_string())
+        .chain(words.windows(2).map(|pair| pair.concat()))
+        .find_map(|word| model_architecture(&word))
+        .unwrap_or(llm::ModelArchitecture::Llama)
+}
+
+// stored sampling settings with the values from the python dict applied on top
+fn sampling_settings(database: &Database, overrides: Option<&PyDict>) -> PyResult<SamplingSettings> {
+    let mut settings = match database.get_sampling_settings() {
+        Ok(s) => s,
+        Err(e) => {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting sampling settings from sqlite database: {:?}", e)));
+        }
+    };
+    if let Some(dict) = overrides {
+        for (key, value) in dict.iter() {
+            match key.extract::<&str>()? {
+                "temperature" => settings.temperature = value.extract()?,
+                "top_k" => settings.top_k = value.extract()?,
+                "top_p" => settings.top_p = value.extract()?,
+                "repeat_penalty" => settings.repeat_penalty = value.extract()?,
+                "repetition_window" => settings.repetition_window = value.extract()?,
+                "seed" => settings.seed = value.extract()?,
+                "max_new_tokens" => settings.max_new_tokens = value.extract()?,
+                other => {
+                    return Err(pyo3::exceptions::PyValueError::new_err(format!("Unknown sampling setting '{}', supported settings are: temperature, top_k, top_p, repeat_penalty, repetition_window, seed, max_new_tokens", other)));
+                }
+            }
+        }
+    }
+    Ok(settings)
+}
+
+// the ai message with the given id (the latest message by default) and the user message it replies to,
+// None when it doesn't reply to one (like the greeting)
+fn reply_messages(database: &Database, ai_message_id: Option<u32>) -> PyResult<(Option<Message>, Message)> {
+    let ai_message = match ai_message_id {
+        Some(id) => database.get_message(id),
+        None => database.get_x_msgs(1).map(|messages| messages.into_iter().next()),
+    };
+    let ai_message = match ai_message {
+        Ok(Some(m)) if m.ai == "true" => m,
+        Ok(Some(m)) => {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!("Message with id {} is not from the ai", m.id)));
+        },
+        Ok(None) => {
+            return Err(pyo3::exceptions::PyValueError::new_err("There is no such message in the active chat"));
+        },
+        Err(e) => {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting message from sqlite database: {:?}", e)));
+        },
+    };
+    let user_message = match database.get_x_msgs_before(ai_message.id, 1) {
+        Ok(messages) => messages.into_iter().next(),
+        Err(e) => {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting message from sqlite database: {:?}", e)));
+        },
+    };
+    Ok((user_message.filter(|m| m.ai != "true"), ai_message))
+}
+
+fn greeting_choice(database: &Database, greeting: Option<usize>, random_greeting: bool) -> PyResult<Greeting> {
+    match (greeting, random_greeting) {
+        (Some(_), true) => Err(pyo3::exceptions::PyValueError::new_err("Choose either a greeting index or a random greeting, not both")),
+        (None, true) => Ok(Greeting::Random),
+        (None, false) => Ok(Greeting::Index(0)),
+        (Some(index), false) => {
+            let greetings = match database.get_greetings() {
+                Ok(g) => g,
+                Err(e) => {
+                    return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting greetings from sqlite database: {:?}", e)));
+                }
+            };
+            if index >= greetings.len() {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("There is no greeting with index {}, the companion has {} greetings", index, greetings.len())));
+            }
+            Ok(Greeting::Index(index))
+        }
+    }
+}
+
+fn lorebook_entry(mut entry: LorebookEntry, fields: Option<&PyDict>) -> PyResult<LorebookEntry> {
+    if let Some(dict) = fields {
+        for (key, value) in dict.iter() {
+            match key.extract::<&str>()? {
+                "name" => entry.name = value.extract()?,
+                "keys" => entry.keys = value.extract()?,
+                "secondary_keys" => entry.secondary_keys = value.extract()?,
+                "content" => entry.content = value.extract()?,
+                "insertion_order" => entry.insertion_order = value.extract()?,
+                "position" => entry.position = value.extract()?,
+                "scan_depth" => entry.scan_depth = value.extract()?,
+                "enabled" => entry.enabled = value.extract()?,
+                "constant" => entry.constant = value.extract()?,
+                "case_sensitive" => entry.case_sensitive = value.extract()?,
+                other => {
+                    return Err(pyo3::exceptions::PyValueError::new_err(format!("Unknown lorebook entry field '{}', supported fields are: name, keys, secondary_keys, content, insertion_order, position, scan_depth, enabled, constant, case_sensitive", other)));
+                }
+            }
+        }
+    }
+    if !LOREBOOK_POSITIONS.contains(&entry.position.as_str()) {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!("Unknown lorebook position '{}', supported positions are: {}", entry.position, LOREBOOK_POSITIONS.join(", "))));
+    }
+    Ok(entry)
+}
+
+// lorebook of an imported character replaces the lorebook of the companion
+fn import_character_book(database: &Database, character_book: Option<&serde_json::Value>) -> PyResult<()> {
+    let (entries, token_budget) = match character_book {
+        Some(book) => entries_from_character_book(book),
+        None => (Vec::new(), None),
+    };
+    match database.add_lorebook_entries(&entries, true) {
+        Ok(_) => {},
+        Err(e) => {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while importing lorebook to sqlite database: {:?}", e)));
+        }
+    };
+    if let Some(lorebook_budget) = token_budget {
+        let mut settings = match database.get_context_settings() {
+            Ok(s) => s,
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while getting context settings from sqlite database: {:?}", e)));
+            }
+        };
+        settings.lorebook_budget = lorebook_budget;
+        match database.change_context_settings(&settings) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!("Error while changing context settings in sqlite database: {:?}", e)));
+            }
+        };
+    }
+    Ok(())
+}
+
+fn print_token(token: &str) -> Result<bool, String> {
+    print!("{token}");
+    std::io::stdout().flush().unwrap();
+    Ok(false)
+}
+
 // works with https://zoltanai.github.io/character-editor/
 // and with https://github.com/Hukasx0/aichar
 #[derive(Serialize, Deserialize)]
 struct CharacterJson {
     name: String,
     description: String,
-    first_mes: String,
-    mes_example: String,
-}
-
-#[derive(Deserialize)]
-struct CharacterCard {
-    name: String,
-    description: String,
+    personality: String,
+    scenario: String,
     first_mes: String,
     mes_example: String,
 }
@@ -0,0 +1,176 @@
+use pyo3::prelude::*;
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+
+// entries are put into the prompt when one of their keys is found in the latest scan_depth messages,
+// if an entry has secondary keys one of them has to be found too, constant entries are always put in
+#[derive(Serialize, Deserialize, Clone)]
+#[pyclass(get_all)]
+pub struct LorebookEntry {
+    pub id: u32,
+    pub name: String,
+    pub keys: Vec<String>,
+    pub secondary_keys: Vec<String>,
+    pub content: String,
+    pub insertion_order: i32,
+    pub position: String,
+    pub scan_depth: u32,
+    pub enabled: bool,
+    pub constant: bool,
+    pub case_sensitive: bool,
+}
+
+impl Default for LorebookEntry {
+    fn default() -> Self {
+        LorebookEntry {
+            id: 0,
+            name: String::new(),
+            keys: Vec::new(),
+            secondary_keys: Vec::new(),
+            content: String::new(),
+            insertion_order: 100,
+            position: "after_persona".to_string(),
+            scan_depth: 4,
+            enabled: true,
+            constant: false,
+            case_sensitive: false,
+        }
+    }
+}
+
+pub const LOREBOOK_POSITIONS: [&str; 4] = ["before_persona", "after_persona", "before_history", "after_history"];
+
+// triggered entries grouped by where they are put in the prompt
+#[derive(Default)]
+pub struct LoreSections {
+    pub before_persona: String,
+    pub after_persona: String,
+    pub before_history: String,
+    pub after_history: String,
+}
+
+fn contains_key(text: &str, key: &str, case_sensitive: bool) -> bool {
+    let key = key.trim();
+    if key.is_empty() {
+        return false;
+    }
+    if case_sensitive {
+        text.contains(key)
+    } else {
+        text.to_lowercase().contains(&key.to_lowercase())
+    }
+}
+
+// messages are ordered from the oldest, the latest scan_depth of them are searched for keys
+pub fn triggered_entries<'a>(entries: &'a [LorebookEntry], messages: &[String]) -> Vec<&'a LorebookEntry> {
+    entries.iter().filter(|entry| {
+        if !entry.enabled {
+            return false;
+        }
+        if entry.constant {
+            return true;
+        }
+        let scanned = messages[messages.len().saturating_sub(entry.scan_depth as usize)..].join("\n");
+        entry.keys.iter().any(|key| contains_key(&scanned, key, entry.case_sensitive))
+            && (entry.secondary_keys.is_empty() || entry.secondary_keys.iter().any(|key| contains_key(&scanned, key, entry.case_sensitive)))
+    }).collect()
+}
+
+// entries with the highest insertion order are kept when the budget is exceeded,
+// returns the sections and how many entries did not fit
+pub fn fit_to_budget(mut entries: Vec<&LorebookEntry>, budget: usize, count_tokens: impl Fn(&str) -> usize) -> (LoreSections, usize, usize) {
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.insertion_order));
+    let mut used: usize = 0;
+    let mut dropped: usize = 0;
+    let mut kept: Vec<&LorebookEntry> = Vec::new();
+    for entry in entries {
+        let tokens = count_tokens(&entry.content);
+        if used + tokens > budget {
+            dropped += 1;
+            continue;
+        }
+        used += tokens;
+        kept.push(entry);
+    }
+    // lower insertion order is put higher in the prompt
+    kept.sort_by_key(|entry| entry.insertion_order);
+    let mut sections = LoreSections::default();
+    for entry in &kept {
+        let section = match entry.position.as_str() {
+            "before_persona" => &mut sections.before_persona,
+            "before_history" => &mut sections.before_history,
+            "after_history" => &mut sections.after_history,
+            _ => &mut sections.after_persona,
+        };
+        section.push_str(&entry.content);
+        section.push('\n');
+    }
+    (sections, kept.len(), dropped)
+}
+
+fn string_list(value: Option<&Value>) -> Vec<String> {
+    match value.and_then(|v| v.as_array()) {
+        Some(list) => list.iter().filter_map(|v| v.as_str()).map(|s| s.to_string()).collect(),
+        None => Vec::new(),
+    }
+}
+
+// character_book of a chara_card_v2/v3 card, positions other than the ones of the spec
+// and scan depths of entries are kept in extensions
+pub fn entries_from_character_book(book: &Value) -> (Vec<LorebookEntry>, Option<usize>) {
+    let default_depth = book.get("scan_depth").and_then(|v| v.as_u64()).unwrap_or(4) as u32;
+    let token_budget = book.get("token_budget").and_then(|v| v.as_u64()).map(|v| v as usize);
+    let mut entries: Vec<LorebookEntry> = Vec::new();
+    for entry in book.get("entries").and_then(|v| v.as_array()).into_iter().flatten() {
+        let extensions = entry.get("extensions");
+        let position = match extensions.and_then(|e| e.get("position")).and_then(|v| v.as_str()) {
+            Some(p) if LOREBOOK_POSITIONS.contains(&p) => p.to_string(),
+            _ => match entry.get("position").and_then(|v| v.as_str()) {
+                Some("before_char") => "before_persona".to_string(),
+                _ => "after_persona".to_string(),
+            },
+        };
+        let selective = entry.get("selective").and_then(|v| v.as_bool()).unwrap_or(false);
+        entries.push(LorebookEntry {
+            id: 0,
+            name: entry.get("name").or_else(|| entry.get("comment")).and_then(|v| v.as_str()).unwrap_or("").to_string(),
+            keys: string_list(entry.get("keys")),
+            secondary_keys: if selective { string_list(entry.get("secondary_keys")) } else { Vec::new() },
+            content: entry.get("content").and_then(|v| v.as_str()).unwrap_or("").to_string(),
+            insertion_order: entry.get("insertion_order").and_then(|v| v.as_i64()).unwrap_or(100) as i32,
+            position,
+            scan_depth: extensions.and_then(|e| e.get("scan_depth")).and_then(|v| v.as_u64()).map_or(default_depth, |v| v as u32),
+            enabled: entry.get("enabled").and_then(|v| v.as_bool()).unwrap_or(true),
+            constant: entry.get("constant").and_then(|v| v.as_bool()).unwrap_or(false),
+            case_sensitive: entry.get("case_sensitive").and_then(|v| v.as_bool()).unwrap_or(false),
+        });
+    }
+    (entries, token_budget)
+}
+
+pub fn character_book_from_entries(name: &str, entries: &[LorebookEntry], token_budget: usize) -> Value {
+    let book_entries: Vec<Value> = entries.iter().map(|entry| json!({
+        "id": entry.id,
+        "name": entry.name,
+        "comment": entry.name,
+        "keys": entry.keys,
+        "secondary_keys": entry.secondary_keys,
+        "selective": !entry.secondary_keys.is_empty(),
+        "content": entry.content,
+        "insertion_order": entry.insertion_order,
+        "enabled": entry.enabled,
+        "constant": entry.constant,
+        "case_sensitive": entry.case_sensitive,
+        "position": if entry.position == "before_persona" { "before_char" } else { "after_char" },
+        "extensions": {
+            "position": entry.position,
+            "scan_depth": entry.scan_depth,
+        },
+    })).collect();
+    json!({
+        "name": name,
+        "token_budget": token_budget,
+        "extensions": {},
+        "entries": book_entries,
+    })
+}
@@ -0,0 +1,350 @@
+use rusqlite::{Connection, Transaction, Result, Error};
+use chrono::Local;
+
+type Migration = fn(&Transaction) -> Result<(), Error>;
+
+// every migration upgrades the schema by one version, PRAGMA user_version holds the version
+// of the database, 0.1.x releases did not set it so their databases are at version 0
+const MIGRATIONS: [Migration; 13] = [
+    base_schema,
+    multiple_companions,
+    chats,
+    memory_settings,
+    memory_fusion,
+    memory_min_score,
+    summaries,
+    character_card_fields,
+    lorebook,
+    swipes,
+    memory_score_scale,
+    character_card_extensions,
+    drop_character_book,
+];
+
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+pub fn schema_version(con: &Connection) -> Result<u32> {
+    con.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+pub fn migrate(con: &mut Connection) -> Result<(), Error> {
+    let version = schema_version(con)?;
+    if version > SCHEMA_VERSION {
+        return Err(Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_MISMATCH),
+            Some(format!("database schema version {} is newer than the newest version supported by this release ({}), update ai-companion-py", version, SCHEMA_VERSION)),
+        ));
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = con.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", i as u32 + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+pub fn has_column(table_name: &str, column_name: &str, con: &Connection) -> Result<bool> {
+    let mut stmt = con.prepare(&format!("PRAGMA table_info({})", table_name))?;
+    let columns = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    for column in columns {
+        if column? == column_name {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// schema of the 0.1.x releases, tables already exist in databases created by them
+fn base_schema(tx: &Transaction) -> Result<(), Error> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS messages (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            ai BOOLEAN NOT NULL,
+            text TEXT NOT NULL,
+            date TEXT NOT NULL
+        )", [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS user (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            persona TEXT NOT NULL
+        )", [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS companion (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            persona TEXT NOT NULL,
+            example_dialogue TEXT NOT NULL,
+            first_message TEXT NOT NULL,
+            long_term_mem INTEGER NOT NULL,
+            short_term_mem INTEGER NOT NULL,
+            roleplay INTEGER NOT NULL,
+            avatar_path STRING NOT NULL
+        )", [],
+    )?;
+    Ok(())
+}
+
+// 0.1.x databases have a single companion and user, every existing message belongs to them
+fn multiple_companions(tx: &Transaction) -> Result<(), Error> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS sampling (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            temperature REAL NOT NULL,
+            top_k INTEGER NOT NULL,
+            top_p REAL NOT NULL,
+            repeat_penalty REAL NOT NULL,
+            repetition_window INTEGER NOT NULL,
+            seed INTEGER,
+            max_new_tokens INTEGER,
+            companion_id INTEGER NOT NULL
+        )", [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS context_settings (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            context_length INTEGER NOT NULL,
+            response_reserve INTEGER NOT NULL,
+            companion_id INTEGER NOT NULL
+        )", [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS prompt_template (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            context TEXT NOT NULL,
+            user_message TEXT NOT NULL,
+            ai_message TEXT NOT NULL,
+            stop_sequences TEXT NOT NULL,
+            companion_id INTEGER NOT NULL
+        )", [],
+    )?;
+    if !has_column("messages", "companion_id", tx)? {
+        tx.execute("ALTER TABLE messages ADD COLUMN companion_id INTEGER NOT NULL DEFAULT 0", [])?;
+        tx.execute("UPDATE messages SET companion_id=(SELECT IFNULL(MIN(id), 0) FROM companion)", [])?;
+    }
+    if !has_column("messages", "user_id", tx)? {
+        tx.execute("ALTER TABLE messages ADD COLUMN user_id INTEGER NOT NULL DEFAULT 0", [])?;
+        tx.execute("UPDATE messages SET user_id=(SELECT IFNULL(MIN(id), 0) FROM user)", [])?;
+    }
+    Ok(())
+}
+
+// existing history of every companion and user is kept in one chat
+fn chats(tx: &Transaction) -> Result<(), Error> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS chat (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            companion_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            created TEXT NOT NULL,
+            last_activity TEXT NOT NULL
+        )", [],
+    )?;
+    if has_column("messages", "chat_id", tx)? {
+        return Ok(());
+    }
+    tx.execute("ALTER TABLE messages ADD COLUMN chat_id INTEGER NOT NULL DEFAULT 0", [])?;
+    let now = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    tx.execute(
+        "INSERT INTO chat (id, name, companion_id, user_id, created, last_activity)
+            SELECT DISTINCT NULL, \"Chat\", companion_id, user_id, ?1, ?1 FROM messages", [&now]
+    )?;
+    tx.execute("UPDATE messages SET chat_id=(SELECT chat.id FROM chat WHERE chat.companion_id=messages.companion_id AND chat.user_id=messages.user_id)", [])?;
+    Ok(())
+}
+
+fn memory_settings(tx: &Transaction) -> Result<(), Error> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS memory_settings (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            retrieval TEXT NOT NULL,
+            companion_id INTEGER NOT NULL
+        )", [],
+    )?;
+    Ok(())
+}
+
+fn memory_fusion(tx: &Transaction) -> Result<(), Error> {
+    tx.execute("ALTER TABLE memory_settings ADD COLUMN fusion TEXT NOT NULL DEFAULT \"rrf\"", [])?;
+    tx.execute("ALTER TABLE memory_settings ADD COLUMN keyword_weight REAL NOT NULL DEFAULT 1.0", [])?;
+    tx.execute("ALTER TABLE memory_settings ADD COLUMN vector_weight REAL NOT NULL DEFAULT 1.0", [])?;
+    Ok(())
+}
+
+fn memory_min_score(tx: &Transaction) -> Result<(), Error> {
+    tx.execute("ALTER TABLE memory_settings ADD COLUMN min_score REAL NOT NULL DEFAULT 0.0", [])?;
+    Ok(())
+}
+
+// running summaries of old messages, every summary covers the messages up to last_message_id
+fn summaries(tx: &Transaction) -> Result<(), Error> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS summaries (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            text TEXT NOT NULL,
+            first_message_id INTEGER NOT NULL,
+            last_message_id INTEGER NOT NULL,
+            created TEXT NOT NULL,
+            companion_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            chat_id INTEGER NOT NULL
+        )", [],
+    )?;
+    tx.execute("CREATE INDEX IF NOT EXISTS summaries_chat ON summaries (chat_id, last_message_id)", [])?;
+    tx.execute("ALTER TABLE memory_settings ADD COLUMN summarize_after INTEGER NOT NULL DEFAULT 0", [])?;
+    Ok(())
+}
+
+// alternate_greetings and tags are json lists, character_book is the json lorebook of the card or empty
+fn character_card_fields(tx: &Transaction) -> Result<(), Error> {
+    for column in ["personality", "scenario", "system_prompt", "post_history_instructions", "creator_notes", "character_book"] {
+        tx.execute(&format!("ALTER TABLE companion ADD COLUMN {} TEXT NOT NULL DEFAULT \"\"", column), [])?;
+    }
+    tx.execute("ALTER TABLE companion ADD COLUMN alternate_greetings TEXT NOT NULL DEFAULT \"[]\"", [])?;
+    tx.execute("ALTER TABLE companion ADD COLUMN tags TEXT NOT NULL DEFAULT \"[]\"", [])?;
+    Ok(())
+}
+
+// keys and secondary_keys are json lists
+fn lorebook(tx: &Transaction) -> Result<(), Error> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS lorebook (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            keys TEXT NOT NULL,
+            secondary_keys TEXT NOT NULL,
+            content TEXT NOT NULL,
+            insertion_order INTEGER NOT NULL,
+            position TEXT NOT NULL,
+            scan_depth INTEGER NOT NULL,
+            enabled BOOLEAN NOT NULL,
+            constant BOOLEAN NOT NULL,
+            case_sensitive BOOLEAN NOT NULL,
+            companion_id INTEGER NOT NULL
+        )", [],
+    )?;
+    tx.execute("CREATE INDEX IF NOT EXISTS lorebook_companion ON lorebook (companion_id)", [])?;
+    tx.execute("ALTER TABLE context_settings ADD COLUMN lorebook_budget INTEGER NOT NULL DEFAULT 512", [])?;
+    Ok(())
+}
+
+// every alternative reply of an ai message, the selected one is also the text of the message,
+// messages that were never swiped have no rows
+fn swipes(tx: &Transaction) -> Result<(), Error> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS swipes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            message_id INTEGER NOT NULL,
+            text TEXT NOT NULL,
+            created TEXT NOT NULL
+        )", [],
+    )?;
+    tx.execute("CREATE INDEX IF NOT EXISTS swipes_message ON swipes (message_id)", [])?;
+    tx.execute("ALTER TABLE messages ADD COLUMN swipe INTEGER NOT NULL DEFAULT 0", [])?;
+    Ok(())
+}
+
+// memory scores are between 0 and 1 now, thresholds set for unbounded bm25 scores are reset
+fn memory_score_scale(tx: &Transaction) -> Result<(), Error> {
+    tx.execute("UPDATE memory_settings SET min_score=0.0 WHERE min_score<0.0 OR min_score>1.0", [])?;
+    Ok(())
+}
+
+// json object with the extensions of the imported character card
+fn character_card_extensions(tx: &Transaction) -> Result<(), Error> {
+    tx.execute("ALTER TABLE companion ADD COLUMN extensions TEXT NOT NULL DEFAULT \"{}\"", [])?;
+    Ok(())
+}
+
+// the character book of imported cards is in the lorebook table, exported cards build it from there
+fn drop_character_book(tx: &Transaction) -> Result<(), Error> {
+    tx.execute("ALTER TABLE companion DROP COLUMN character_book", [])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // database created by the 0.1.x releases, without user_version
+    fn legacy_database() -> Connection {
+        let con = Connection::open_in_memory().unwrap();
+        con.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                ai BOOLEAN NOT NULL,
+                text TEXT NOT NULL,
+                date TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS user (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL,
+                persona TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS companion (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL,
+                persona TEXT NOT NULL,
+                example_dialogue TEXT NOT NULL,
+                first_message TEXT NOT NULL,
+                long_term_mem INTEGER NOT NULL,
+                short_term_mem INTEGER NOT NULL,
+                roleplay INTEGER NOT NULL,
+                avatar_path STRING NOT NULL
+            );
+            INSERT INTO companion (id, name, persona, example_dialogue, first_message, long_term_mem, short_term_mem, roleplay, avatar_path)
+                VALUES (NULL, \"Assistant\", \"{{char}} is an assistant\", \"\", \"Hello {{user}}\", 2, 5, 1, \"/assets/companion_avatar-4rust.jpg\");
+            INSERT INTO user (id, name, persona) VALUES (NULL, \"user\", \"{{user}} is chatting with {{char}}\");
+            INSERT INTO messages (id, ai, text, date) VALUES (NULL, \"true\", \"Hello user\", \"Monday 02.10.2023 12:00\");
+            INSERT INTO messages (id, ai, text, date) VALUES (NULL, \"false\", \"Hi\", \"Monday 02.10.2023 12:01\");
+            INSERT INTO messages (id, ai, text, date) VALUES (NULL, \"true\", \"How can i help you?\", \"Monday 02.10.2023 12:01\");"
+        ).unwrap();
+        con
+    }
+
+    #[test]
+    fn upgrades_legacy_database() {
+        let mut con = legacy_database();
+        assert_eq!(schema_version(&con).unwrap(), 0);
+        migrate(&mut con).unwrap();
+        assert_eq!(schema_version(&con).unwrap(), SCHEMA_VERSION);
+        let companion_id: u32 = con.query_row("SELECT id FROM companion", [], |row| row.get(0)).unwrap();
+        let user_id: u32 = con.query_row("SELECT id FROM user", [], |row| row.get(0)).unwrap();
+        let (chats, chat_id, chat_name): (u32, u32, String) = con.query_row("SELECT COUNT(*), MIN(id), MIN(name) FROM chat", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap();
+        assert_eq!(chats, 1);
+        assert_eq!(chat_name, "Chat");
+        let mut stmt = con.prepare("SELECT companion_id, user_id, chat_id FROM messages").unwrap();
+        let messages: Vec<(u32, u32, u32)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap().map(|m| m.unwrap()).collect();
+        assert_eq!(messages.len(), 3);
+        for message in messages {
+            assert_eq!(message, (companion_id, user_id, chat_id));
+        }
+        let (chat_companion_id, chat_user_id): (u32, u32) = con.query_row("SELECT companion_id, user_id FROM chat", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        assert_eq!((chat_companion_id, chat_user_id), (companion_id, user_id));
+        assert!(has_column("companion", "extensions", &con).unwrap());
+        assert!(!has_column("companion", "character_book", &con).unwrap());
+    }
+
+    #[test]
+    fn migrating_again_changes_nothing() {
+        let mut con = legacy_database();
+        migrate(&mut con).unwrap();
+        migrate(&mut con).unwrap();
+        assert_eq!(schema_version(&con).unwrap(), SCHEMA_VERSION);
+        let chats: u32 = con.query_row("SELECT COUNT(*) FROM chat", [], |row| row.get(0)).unwrap();
+        assert_eq!(chats, 1);
+    }
+
+    #[test]
+    fn rejects_newer_database() {
+        let mut con = legacy_database();
+        con.pragma_update(None, "user_version", SCHEMA_VERSION + 1).unwrap();
+        assert!(migrate(&mut con).is_err());
+        assert_eq!(schema_version(&con).unwrap(), SCHEMA_VERSION + 1);
+    }
+}
@@ -1,106 +1,379 @@
 use pyo3::prelude::*;
 use llm::Model;
-use llm::models::Llama;
-use std::io::Write;
-use chrono::{DateTime, Local};
+use chrono::{Local, TimeZone};
 use crate::Database;
-use crate::database::{Message, CompanionData, UserData};
-use crate::vectordb::VectorDatabase;
+use std::path::PathBuf;
+use crate::database::{Message, CompanionData, UserData, SamplingSettings, ContextSettings};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use std::sync::{Arc, Mutex};
+use crate::vectordb::{VectorDatabase, MemoryEntry, MemoryFilter};
+use crate::template::{PromptTemplate, PromptSections};
+use crate::summary::update_summary;
+use crate::lorebook::{LorebookEntry, LoreSections, triggered_entries, fit_to_budget};
 
 #[pyclass]
 pub struct Companion {
-    pub ai_model: Option<Llama>,
-    pub is_llama2: bool,
+    pub ai_model: Option<Box<dyn Model>>,
+    pub embedding_model: Option<Box<dyn Model>>,
+    pub database: Database,
+    pub memory_path: PathBuf,
+    // long-term memory of the active companion
+    pub memory: VectorDatabase,
+    pub last_context_report: ContextReport,
 }
 
-pub fn prompt_rs(companion_py: &Companion, text_prompt: &str) -> Result<String, String> {
-    let vector = match VectorDatabase::connect() {
-        Ok(vd) => vd,
+impl Companion {
+    // every companion has its own long-term memory index
+    pub fn companion_memory_path(&self) -> PathBuf {
+        self.memory_path_for(self.database.companion_id)
+    }
+
+    pub fn memory_path_for(&self, companion_id: u32) -> PathBuf {
+        self.memory_path.join(format!("companion_{}", companion_id))
+    }
+
+    // embedding of the text for vector search in long-term memory, computed with the embedding model
+    // or with the chat model if no separate embedding model is loaded
+    pub fn embed(&self, text: &str) -> Option<Vec<f32>> {
+        let model = self.embedding_model.as_ref().or(self.ai_model.as_ref())?.as_ref();
+        let tokens: Vec<llm::TokenId> = match model.tokenizer().tokenize(text, true) {
+            Ok(t) => t.iter().map(|(_, token)| *token).take(model.context_size()).collect(),
+            Err(e) => {
+                eprintln!("Error while tokenizing text for long-term memory embedding: {}", e);
+                return None;
+            }
+        };
+        let mut session = model.start_session(Default::default());
+        let mut output_request = llm::OutputRequest {
+            all_logits: None,
+            embeddings: Some(Vec::new()),
+        };
+        model.evaluate(&mut session, &tokens, &mut output_request);
+        output_request.embeddings
+    }
+
+    pub fn memory_entry(&self, text: &str, source: &str, message_ids: Vec<u32>) -> MemoryEntry {
+        MemoryEntry {
+            id: 0,
+            text: text.to_string(),
+            timestamp: Some(Local::now().timestamp()),
+            companion_id: Some(self.database.companion_id),
+            chat_id: Some(self.database.chat_id),
+            user_id: Some(self.database.user_id),
+            source: source.to_string(),
+            message_ids,
+            importance: 1.0,
+        }
+    }
+
+    // embeddings are only needed when long-term memory is searched by vectors
+    pub fn embed_for_memory(&self, text: &str) -> Option<Vec<f32>> {
+        match self.database.get_memory_settings() {
+            Ok(settings) if settings.retrieval != "bm25" => self.embed(text),
+            _ => None,
+        }
+    }
+}
+
+// what had to be left out of the last prompt to fit into the context window
+#[derive(Clone, Default)]
+#[pyclass(get_all)]
+pub struct ContextReport {
+    pub context_length: usize,
+    pub response_reserve: usize,
+    pub prompt_tokens: usize,
+    pub dropped_memories: usize,
+    pub dropped_messages: usize,
+    pub dropped_example_dialogue: bool,
+    pub lorebook_entries: usize,
+    pub dropped_lorebook_entries: usize,
+}
+
+// date of the entry is put before it, older entries already have it in their text
+fn format_memory(entry: &MemoryEntry) -> String {
+    match entry.timestamp.and_then(|timestamp| Local.timestamp_opt(timestamp, 0).single()) {
+        Some(date) => format!("{}{}", date.format("* at %A %d.%m.%Y %H:%M *\n"), entry.text),
+        None => entry.text.clone(),
+    }
+}
+
+pub fn count_tokens(model: &dyn Model, text: &str) -> usize {
+    match model.tokenizer().tokenize(text, false) {
+        Ok(tokens) => tokens.len(),
+        Err(_) => text.len() / 4, // rough estimate if the text can't be tokenized
+    }
+}
+
+// tokens used by the prompt without any memories and messages
+fn fixed_tokens(model: &dyn Model, template: &PromptTemplate, sections: &PromptSections) -> usize {
+    count_tokens(model, &template.render(&PromptSections {
+        memories: &[],
+        history: &[],
+        ..*sections
+    }))
+}
+
+// drops the lowest scoring memories, then the oldest messages (always keeping the latest one)
+// and at last the example dialogue until the prompt fits into the token budget
+fn fit_to_context<'a>(model: &dyn Model, template: &PromptTemplate, sections: &mut PromptSections<'a>, memories: &'a mut Vec<String>, history: &'a mut Vec<(bool, String)>, settings: &ContextSettings) -> Result<(String, ContextReport), String> {
+    let budget = settings.context_length.saturating_sub(settings.response_reserve);
+    let mut report = ContextReport {
+        context_length: settings.context_length,
+        response_reserve: settings.response_reserve,
+        ..Default::default()
+    };
+    let fixed = fixed_tokens(model, template, sections);
+    let mut memory_tokens: Vec<usize> = memories.iter().map(|m| count_tokens(model, m)).collect();
+    let mut history_tokens: Vec<usize> = history.iter().map(|(ai, text)| count_tokens(model, &template.render_message(*ai, text))).collect();
+    let total = |fixed: usize, memory_tokens: &[usize], history_tokens: &[usize]| fixed + memory_tokens.iter().sum::<usize>() + history_tokens.iter().sum::<usize>();
+    while total(fixed, &memory_tokens, &history_tokens) > budget && !memory_tokens.is_empty() {
+        memory_tokens.pop();
+        memories.pop();
+        report.dropped_memories += 1;
+    }
+    while total(fixed, &memory_tokens, &history_tokens) > budget && history_tokens.len() > 1 {
+        history_tokens.remove(0);
+        history.remove(0);
+        report.dropped_messages += 1;
+    }
+    if total(fixed, &memory_tokens, &history_tokens) > budget && !sections.example_dialogue.is_empty() {
+        sections.example_dialogue = "";
+        report.dropped_example_dialogue = true;
+    }
+    sections.memories = memories;
+    sections.history = history;
+    let prompt = template.render(sections);
+    report.prompt_tokens = count_tokens(model, &prompt);
+    if report.prompt_tokens > budget {
+        return Err(format!("Prompt is too long to fit into the context window ({} tokens, {} available after reserving {} tokens for the response), shorten the persona or increase the context length", report.prompt_tokens, budget, settings.response_reserve));
+    }
+    Ok((prompt, report))
+}
+
+// called for every chunk of generated text, returning true halts the generation,
+// an error halts it too and nothing of the reply is stored
+pub type TokenCallback<'a> = dyn FnMut(&str) -> Result<bool, String> + 'a;
+
+pub fn inference_parameters(settings: &SamplingSettings) -> Result<llm::InferenceParameters, String> {
+    let sampler_config = format!("repetition:penalty={}:last_n={}/topk:k={}/topp:p={}/temperature:temperature={}",
+        settings.repeat_penalty, settings.repetition_window, settings.top_k, settings.top_p, settings.temperature);
+    let mut samplers: llm::samplers::ConfiguredSamplers = match sampler_config.parse() {
+        Ok(s) => s,
         Err(e) => {
-            eprintln!("Error while connecting to tantivy: {}", e);
-            panic!();
+            return Err(format!("Error while configuring samplers: {:?}", e));
         }
     };
-    let local: DateTime<Local> = Local::now();
-    let formatted_date = local.format("* at %A %d.%m.%Y %H:%M *\n").to_string();
+    samplers.ensure_default_slots();
+    Ok(llm::InferenceParameters {
+        sampler: Arc::new(Mutex::new(samplers.builder.into_chain())),
+    })
+}
+
+// what the generated text becomes
+#[derive(Clone, Copy)]
+pub enum Reply {
+    // a new message at the end of the chat
+    New,
+    // another swipe of the ai message with this id, generated from the messages before it
+    Swipe(u32),
+    // more text appended to the ai message with this id
+    Continue(u32),
+}
+
+impl Reply {
+    fn history(&self, database: &Database, msgs_limit: u32) -> rusqlite::Result<Vec<Message>> {
+        match self {
+            Reply::New => database.get_x_msgs(msgs_limit),
+            Reply::Swipe(message_id) | Reply::Continue(message_id) => database.get_x_msgs_before(*message_id, msgs_limit),
+        }
+    }
+}
 
-    let llama = companion_py.ai_model.as_ref().unwrap();
-    
-    let mut session = llama.start_session(Default::default());
+// long-term memory entry of a reply, replace_previous removes the entries of earlier texts of the same message
+pub fn remember_reply(companion_py: &Companion, user_text: &str, user_message_id: Option<u32>, companion_text: &str, companion_message_id: u32, replace_previous: bool) -> Result<(), String> {
+    if replace_previous {
+        match companion_py.memory.delete_message_entries(companion_message_id) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(format!("Error while removing previous reply from long-term memory: {:?}", e));
+            },
+        };
+    }
+    // replies that don't answer a user message (like a continued greeting) are remembered alone
+    let memory_text = match user_message_id {
+        Some(_) => format!("{}: {}\n{}: {}\n", "{{user}}", user_text, "{{char}}", companion_text),
+        None => format!("{}: {}\n", "{{char}}", companion_text),
+    };
+    let message_ids = user_message_id.into_iter().chain(std::iter::once(companion_message_id)).collect();
+    match companion_py.memory.add_entry(&companion_py.memory_entry(&memory_text, "conversation", message_ids), companion_py.embed_for_memory(&memory_text).as_deref()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error while adding message to long-term memory: {:?}", e)),
+    }
+}
+
+// user_message_id is the id of the message being replied to, stored with the long-term memory entry,
+// without a user message text_prompt is empty and long-term memory is not searched
+pub fn prompt_rs(companion_py: &Companion, text_prompt: &str, user_message_id: Option<u32>, reply: Reply, sampling: &SamplingSettings, on_token: &mut TokenCallback) -> Result<(String, ContextReport), String> {
+    let parameters = inference_parameters(sampling)?;
+    let mut rng: StdRng = match sampling.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let vector = &companion_py.memory;
+    let model = match companion_py.ai_model.as_ref() {
+        Some(m) => m.as_ref(),
+        None => {
+            return Err("No ai model is loaded, load one with load_model() first".to_string());
+        }
+    };
+
+    let mut session = model.start_session(Default::default());
     println!("Generating ai response...");
-    let companion: CompanionData = match Database::get_companion_data() {
+    let companion: CompanionData = match companion_py.database.get_companion_data() {
         Ok(cd) => cd,
         Err(e) => {
             eprintln!("Error while getting companion data from sqlite database: {}", e);
             panic!();
         }
     };
-    let user: UserData = match Database::get_user_data() {
+    let user: UserData = match companion_py.database.get_user_data() {
         Ok(ud) => ud,
         Err(e) => {
             eprintln!("Error while getting user data from sqlite database: {}", e);
             panic!();
         }
     };
-    let mut base_prompt: String;
+    let template: PromptTemplate = match companion_py.database.get_prompt_template() {
+        Ok(t) => t,
+        Err(e) => {
+            return Err(format!("Error while getting prompt template from sqlite database: {}", e));
+        }
+    };
     let mut rp: &str = "";
     if companion.roleplay == 1 {
         rp = "gestures and other non-verbal actions are written between asterisks (for example, *waves hello* or *moves closer*)";
     }
-    if companion_py.is_llama2 {
-        base_prompt = 
-        format!("<<SYS>>\nYou are {}, {}\nyou are talking with {}, {} is {}\n{}\n[INST]\n{}\n[/INST]",
-                companion.name, companion.persona.replace("{{char}}", &companion.name).replace("{{user}}", &user.name), user.name, user.name, user.persona.replace("{{char}}", &companion.name).replace("{{user}}", &user.name), rp, companion.example_dialogue.replace("{{char}}", &companion.name).replace("{{user}}", &user.name));
-    } else {
-        base_prompt = 
-        format!("Text transcript of a conversation between {} and {}. {}\n{}'s Persona: {}\n{}'s Persona: {}\n<START>{}\n<START>\n", 
-                                            user.name, companion.name, rp, user.name, user.persona.replace("{{char}}", &companion.name).replace("{{user}}", &user.name), companion.name, companion.persona.replace("{{char}}", &companion.name).replace("{{user}}", &user.name), companion.example_dialogue.replace("{{char}}", &companion.name).replace("{{user}}", &user.name));
-    }
+    let memory_settings = match companion_py.database.get_memory_settings() {
+        Ok(ms) => ms,
+        Err(e) => {
+            return Err(format!("Error while getting memory settings from sqlite database: {}", e));
+        }
+    };
     let mut abstract_memory: Vec<String> = Vec::new();
-    if companion.long_term_mem != 0 {
-        abstract_memory = match vector.get_matches(text_prompt, companion.long_term_mem) {
-            Ok(m) => m,
+    if companion.long_term_mem != 0 && !text_prompt.is_empty() {
+        // memories of other users are not recalled, neither is the entry of the reply being replaced or continued
+        let exclude_message_id = match reply {
+            Reply::New => None,
+            Reply::Swipe(message_id) | Reply::Continue(message_id) => Some(message_id),
+        };
+        let filter = MemoryFilter { user_id: Some(companion_py.database.user_id), exclude_message_id, ..Default::default() };
+        let query_embedding = if memory_settings.retrieval != "bm25" { companion_py.embed(text_prompt) } else { None };
+        abstract_memory = match vector.get_matches(text_prompt, query_embedding.as_deref(), companion.long_term_mem, &memory_settings, &filter, "relevance") {
+            Ok(m) => m.iter()
+                .map(|(_, entry)| format_memory(entry))
+                .collect(),
             Err(e) => {
                 eprintln!("Error while getting messages from long-term memory: {}", e);
                 Vec::new() // If there is a error with long-term memory, just display error, don't interrupt generation
             }
         };
     }
-    for message in abstract_memory {
-        base_prompt += &message.replace("{{char}}", &companion.name).replace("{{user}}", &user.name);
-    }
-    let ai_memory: Vec<Message> = match Database::get_x_msgs(companion.short_term_mem) {
+    let ai_memory: Vec<Message> = match reply.history(&companion_py.database, companion.short_term_mem) {
         Ok(msgs) => msgs,
         Err(e) => {
             return Err(format!("Error while getting messages from database/short-term memory: {}", e));
         }
     };
-    if companion_py.is_llama2 {
-        for message in ai_memory {
-            let prefix = if message.ai == "true" { &companion.name } else { &user.name };
-            let text = message.text;
-            let formatted_message = format!("{}: {}\n", prefix, text);
-            base_prompt += &("[INST]".to_owned() + &formatted_message + "[/INST]\n");
+    let context_settings: ContextSettings = match companion_py.database.get_context_settings() {
+        Ok(cs) => cs,
+        Err(e) => {
+            return Err(format!("Error while getting context settings from sqlite database: {}", e));
         }
-        base_prompt += "<</SYS>>";
-    } else {
-        for message in ai_memory {
-            let prefix = if message.ai == "true" { &companion.name } else { &user.name };
-            let text = message.text;
-            let formatted_message = format!("{}: {}\n", prefix, text);
-            base_prompt += &formatted_message;
+    };
+    let summary: String = match update_summary(companion_py, &template, &companion, &user, sampling, memory_settings.summarize_after, context_settings.context_length) {
+        Ok(s) => s.unwrap_or_default(),
+        Err(e) => {
+            eprintln!("{}", e);
+            String::new() // like long-term memory, a failed summary doesn't interrupt generation
+        }
+    };
+    let mut history: Vec<(bool, String)> = ai_memory.into_iter().map(|message| (message.ai == "true", message.text)).collect();
+    let lorebook: Vec<LorebookEntry> = match companion_py.database.get_lorebook_entries() {
+        Ok(entries) => entries,
+        Err(e) => {
+            return Err(format!("Error while getting lorebook entries from sqlite database: {}", e));
         }
+    };
+    let mut lore = LoreSections::default();
+    let (mut lorebook_entries, mut dropped_lorebook_entries) = (0, 0);
+    if lorebook.iter().any(|entry| entry.enabled) {
+        let scan_depth = lorebook.iter().map(|entry| entry.scan_depth).max().unwrap_or(0);
+        let scanned: Vec<String> = match reply.history(&companion_py.database, scan_depth) {
+            Ok(msgs) => msgs.into_iter().map(|message| message.text).collect(),
+            Err(e) => {
+                return Err(format!("Error while getting messages to scan for lorebook keys from sqlite database: {}", e));
+            }
+        };
+        (lore, lorebook_entries, dropped_lorebook_entries) = fit_to_budget(triggered_entries(&lorebook, &scanned), context_settings.lorebook_budget, |text| count_tokens(model, text));
+    }
+    let persona = if companion.personality.is_empty() {
+        companion.persona.clone()
+    } else {
+        format!("{}\n{{{{char}}}}'s personality: {}", companion.persona, companion.personality)
+    };
+    let mut sections = PromptSections {
+        char_name: &companion.name,
+        user_name: &user.name,
+        persona: &persona,
+        user_persona: &user.persona,
+        scenario: &companion.scenario,
+        system_prompt: &companion.system_prompt,
+        post_history_instructions: &companion.post_history_instructions,
+        example_dialogue: &companion.example_dialogue,
+        roleplay: rp,
+        summary: &summary,
+        lore: &lore,
+        memories: &[],
+        history: &[],
+    };
+    // the unfinished message is not part of the history, its tokens are reserved together with the response
+    let unfinished: Option<String> = match reply {
+        Reply::Continue(message_id) => match companion_py.database.get_message(message_id) {
+            Ok(Some(message)) => Some(message.text),
+            Ok(None) => {
+                return Err(format!("There is no message with id {} to continue", message_id));
+            },
+            Err(e) => {
+                return Err(format!("Error while getting message to continue from sqlite database: {}", e));
+            }
+        },
+        _ => None,
+    };
+    let unfinished_prompt = unfinished.as_ref().map(|text| template.unfinished_ai_message(text).replace("{{char}}", &companion.name).replace("{{user}}", &user.name));
+    let mut fit_settings = context_settings.clone();
+    fit_settings.response_reserve += unfinished_prompt.as_ref().map_or(0, |text| count_tokens(model, text));
+    let (mut base_prompt, mut context_report) = fit_to_context(model, &template, &mut sections, &mut abstract_memory, &mut history, &fit_settings)?;
+    if let Some(unfinished_prompt) = &unfinished_prompt {
+        base_prompt += unfinished_prompt;
+        context_report.prompt_tokens = count_tokens(model, &base_prompt);
+        context_report.response_reserve = context_settings.response_reserve;
     }
+    context_report.lorebook_entries = lorebook_entries;
+    context_report.dropped_lorebook_entries = dropped_lorebook_entries;
+    let stop_sequences = template.stop_sequences(&companion.name, &user.name);
     let mut end_of_generation = String::new();
-    let eog = format!("\n{}:", user.name);
+    let mut emitted: usize = 0;
+    let mut halted = false;
+    let mut callback_error: Option<String> = None;
     let res = session.infer::<std::convert::Infallible>(
-        llama,
-        &mut rand::thread_rng(),
+        model,
+        &mut rng,
         &llm::InferenceRequest {
-            prompt: llm::Prompt::Text(&format!("{}{}:", &base_prompt, companion.name)),
-            parameters: &llm::InferenceParameters::default(),
+            prompt: llm::Prompt::Text(&base_prompt),
+            parameters: &parameters,
             play_back_previous_tokens: false,
-            maximum_token_count: None,
+            maximum_token_count: Some(sampling.max_new_tokens.unwrap_or(context_settings.response_reserve).min(context_settings.response_reserve)),
         },
         &mut Default::default(),
         |t| {
@@ -108,20 +381,58 @@ pub fn prompt_rs(companion_py: &Companion, text_prompt: &str) -> Result<String,
                 llm::InferenceResponse::SnapshotToken(_) => {/*print!("{token}");*/}
                 llm::InferenceResponse::PromptToken(_) => {/*print!("{token}");*/}
                 llm::InferenceResponse::InferredToken(token) => {
-                    //x = x.clone()+&token;
                     end_of_generation.push_str(&token);
-                    print!("{token}");
-                    if end_of_generation.contains(&eog) {
-                        return Ok(llm::InferenceFeedback::Halt);          
+                    if stop_sequences.iter().any(|stop| end_of_generation.contains(stop)) {
+                        return Ok(llm::InferenceFeedback::Halt);
+                    }
+                    // hold back text that could still turn out to be the beginning of a stop sequence
+                    let safe_end = end_of_generation.char_indices()
+                        .map(|(i, _)| i)
+                        .find(|&i| i >= emitted && stop_sequences.iter().any(|stop| stop.starts_with(&end_of_generation[i..])))
+                        .unwrap_or(end_of_generation.len());
+                    if safe_end > emitted {
+                        let halt = on_token(&end_of_generation[emitted..safe_end]);
+                        emitted = safe_end;
+                        match halt {
+                            Ok(false) => {},
+                            Ok(true) => {
+                                halted = true;
+                                return Ok(llm::InferenceFeedback::Halt);
+                            },
+                            Err(e) => {
+                                callback_error = Some(e);
+                                return Ok(llm::InferenceFeedback::Halt);
+                            },
+                        }
                     }
                 }
                 llm::InferenceResponse::EotToken => {}
             }
-            std::io::stdout().flush().unwrap();
             Ok(llm::InferenceFeedback::Continue)
         }
     );
-    let x: String = end_of_generation.replace(&eog, "");
+    let stop_position = stop_sequences.iter().filter_map(|stop| end_of_generation.find(stop)).min();
+    if halted {
+        end_of_generation.truncate(emitted);
+    } else if callback_error.is_none() {
+        if let Some(position) = stop_position {
+            end_of_generation.truncate(position);
+        }
+        if emitted < end_of_generation.len() {
+            if let Err(e) = on_token(&end_of_generation[emitted..]) {
+                callback_error = Some(e);
+            }
+        }
+    }
+    // the partial reply of a failed callback is not added to the chat or to long-term memory
+    if let Some(e) = callback_error {
+        return Err(e);
+    }
+    // a continuation can start in the middle of a word, so its leading whitespace is kept
+    let x: String = match reply {
+        Reply::Continue(_) => end_of_generation.clone(),
+        _ => end_of_generation.trim_start().to_string(),
+    };
     match res {
         Ok(result) => println!("\n\nInference stats:\n{result}"),
         Err(err) => println!("\n{err}"),
@@ -130,17 +441,35 @@ pub fn prompt_rs(companion_py: &Companion, text_prompt: &str) -> Result<String,
     .split(&format!("\n{}: ", &companion.name))
     .next()
     .unwrap_or("");
-    match Database::add_message(companion_text, true) {
-        Ok(_) => {},
-        Err(e) => {
-            return Err(format!("Error while adding message to database/short-term memory: {:?}", e));
+    let companion_message_id = match reply {
+        Reply::New => match companion_py.database.add_message(companion_text, true) {
+            Ok(id) => id,
+            Err(e) => {
+                return Err(format!("Error while adding message to database/short-term memory: {:?}", e));
+            },
         },
+        Reply::Swipe(message_id) => match companion_py.database.add_swipe(message_id, companion_text) {
+            Ok(_) => message_id,
+            Err(e) => {
+                return Err(format!("Error while adding swipe to sqlite database: {:?}", e));
+            },
+        },
+        Reply::Continue(message_id) => message_id,
     };
-    match vector.add_entry(&format!("{}{}: {}\n{}: {}\n", formatted_date, "{{user}}", text_prompt, "{{char}}", &companion_text)) {
-        Ok(_) => {},
-        Err(e) => {
-            return Err(format!("Error while adding message to long-term memory: {:?}", e));
+    // continued messages are returned and remembered as a whole
+    let companion_text = match unfinished {
+        Some(text) => {
+            let continued = text + companion_text;
+            match companion_py.database.modify_message(&continued, companion_message_id) {
+                Ok(_) => {},
+                Err(e) => {
+                    return Err(format!("Error while appending to message in sqlite database: {:?}", e));
+                },
+            };
+            continued
         },
+        None => companion_text.to_string(),
     };
-    Ok(companion_text.to_string())
+    remember_reply(companion_py, text_prompt, user_message_id, &companion_text, companion_message_id, !matches!(reply, Reply::New))?;
+    Ok((companion_text, context_report))
 }
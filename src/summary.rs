@@ -0,0 +1,117 @@
+use llm::Model;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use crate::database::{CompanionData, UserData, Message, SamplingSettings};
+use crate::template::PromptTemplate;
+use crate::prompt::{Companion, inference_parameters, count_tokens};
+
+// maximum length of a summary
+const SUMMARY_TOKENS: usize = 256;
+
+const SUMMARY_INSTRUCTION: &str = "Write a short summary of the conversation between {{user}} and {{char}} below, keep the important facts, names and events. If there is a previous summary, write a new summary that includes it.";
+
+// once at least summarize_after messages that are not summarized yet have left short-term memory,
+// they are summarized together with the previous summary, so the latest summary covers everything before it
+pub fn update_summary(companion_py: &Companion, template: &PromptTemplate, companion: &CompanionData, user: &UserData, sampling: &SamplingSettings, summarize_after: u32, context_length: usize) -> Result<Option<String>, String> {
+    let previous = match companion_py.database.get_latest_summary() {
+        Ok(s) => s,
+        Err(e) => {
+            return Err(format!("Error while getting summary from sqlite database: {}", e));
+        }
+    };
+    let previous_text = previous.as_ref().map(|summary| summary.text.clone());
+    if summarize_after == 0 {
+        return Ok(previous_text);
+    }
+    let model: &dyn Model = match companion_py.ai_model.as_ref() {
+        Some(m) => m.as_ref(),
+        None => {
+            return Err("No ai model is loaded, load one with load_model() first".to_string());
+        }
+    };
+    let summarized_until = previous.as_ref().map_or(0, |summary| summary.last_message_id);
+    let mut messages: Vec<Message> = match companion_py.database.get_unsummarized_messages(summarized_until, companion.short_term_mem) {
+        Ok(m) => m,
+        Err(e) => {
+            return Err(format!("Error while getting messages to summarize from sqlite database: {}", e));
+        }
+    };
+    if messages.len() < summarize_after as usize {
+        return Ok(previous_text);
+    }
+    // the rest of the messages is summarized next time
+    let mut summary_prompt = build_prompt(template, previous_text.as_deref(), &messages, companion, user);
+    while count_tokens(model, &summary_prompt) + SUMMARY_TOKENS > context_length && messages.len() > 1 {
+        messages.pop();
+        summary_prompt = build_prompt(template, previous_text.as_deref(), &messages, companion, user);
+    }
+    println!("Summarizing {} messages...", messages.len());
+    let text = generate(model, &summary_prompt, &template.stop_sequences(&companion.name, &user.name), sampling)?;
+    if text.is_empty() {
+        return Ok(previous_text);
+    }
+    let (first_message_id, last_message_id) = (messages[0].id, messages[messages.len() - 1].id);
+    match companion_py.database.add_summary(&text, first_message_id, last_message_id) {
+        Ok(_) => {},
+        Err(e) => {
+            return Err(format!("Error while adding summary to sqlite database: {:?}", e));
+        }
+    };
+    let memory_text = format!("Summary of the conversation: {}\n", text);
+    match companion_py.memory.add_entry(&companion_py.memory_entry(&memory_text, "summary", Vec::new()), companion_py.embed_for_memory(&memory_text).as_deref()) {
+        Ok(_) => {},
+        Err(e) => {
+            return Err(format!("Error while adding summary to long-term memory: {:?}", e));
+        }
+    };
+    Ok(Some(text))
+}
+
+fn build_prompt(template: &PromptTemplate, previous: Option<&str>, messages: &[Message], companion: &CompanionData, user: &UserData) -> String {
+    let mut instruction = SUMMARY_INSTRUCTION.to_string();
+    if let Some(previous) = previous {
+        instruction += &format!("\nPrevious summary: {}", previous);
+    }
+    for message in messages {
+        instruction += &format!("\n{}: {}", if message.ai == "true" { "{{char}}" } else { "{{user}}" }, message.text);
+    }
+    let prompt = template.render_message(false, &instruction) + &template.ai_prefix();
+    prompt.replace("{{char}}", &companion.name).replace("{{user}}", &user.name)
+}
+
+fn generate(model: &dyn Model, prompt: &str, stop_sequences: &[String], sampling: &SamplingSettings) -> Result<String, String> {
+    let parameters = inference_parameters(sampling)?;
+    let mut rng: StdRng = match sampling.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let mut session = model.start_session(Default::default());
+    let mut text = String::new();
+    let res = session.infer::<std::convert::Infallible>(
+        model,
+        &mut rng,
+        &llm::InferenceRequest {
+            prompt: llm::Prompt::Text(prompt),
+            parameters: &parameters,
+            play_back_previous_tokens: false,
+            maximum_token_count: Some(SUMMARY_TOKENS),
+        },
+        &mut Default::default(),
+        |t| {
+            if let llm::InferenceResponse::InferredToken(token) = t {
+                text.push_str(&token);
+                if stop_sequences.iter().any(|stop| text.contains(stop)) {
+                    return Ok(llm::InferenceFeedback::Halt);
+                }
+            }
+            Ok(llm::InferenceFeedback::Continue)
+        }
+    );
+    if let Err(e) = res {
+        return Err(format!("Error while generating summary: {}", e));
+    }
+    if let Some(position) = stop_sequences.iter().filter_map(|stop| text.find(stop)).min() {
+        text.truncate(position);
+    }
+    Ok(text.trim().to_string())
+}
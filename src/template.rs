@@ -0,0 +1,331 @@
+use pyo3::prelude::*;
+use serde::{Serialize, Deserialize};
+use crate::lorebook::LoreSections;
+
+// placeholders that can be used in the context of a template:
+// {{system_prompt}}, {{persona}}, {{user_persona}}, {{scenario}}, {{example_dialogue}}, {{summary}}, {{memories}}, {{history}}, {{roleplay}}
+// {{message}} is used in user_message and ai_message, {{char}} and {{user}} can be used everywhere
+// post-history instructions of the character are put right after the history,
+// triggered lorebook entries around the persona or the history depending on their position
+#[derive(Serialize, Deserialize, Clone)]
+#[pyclass(get_all)]
+pub struct PromptTemplate {
+    pub name: String,
+    pub context: String,
+    pub user_message: String,
+    pub ai_message: String,
+    pub stop_sequences: Vec<String>,
+    // beginning of the reply when it differs from the beginning of ai_message
+    pub reply_prefix: Option<String>,
+}
+
+// built-in templates that can be selected, custom templates are set with their text
+pub const TEMPLATE_NAMES: [&str; 7] = ["pygmalion", "llama2", "chatml", "alpaca", "vicuna", "mistral", "zephyr"];
+
+const SYSTEM: &str = "{{system_prompt}}You are {{char}}, {{persona}}\n{{scenario}}You are talking with {{user}}, {{user_persona}}\n{{roleplay}}\nExample dialogue:\n{{example_dialogue}}\n{{summary}}{{memories}}";
+
+pub struct PromptSections<'a> {
+    pub char_name: &'a str,
+    pub user_name: &'a str,
+    pub persona: &'a str,
+    pub user_persona: &'a str,
+    pub scenario: &'a str,
+    pub system_prompt: &'a str,
+    pub post_history_instructions: &'a str,
+    pub example_dialogue: &'a str,
+    pub roleplay: &'a str,
+    pub summary: &'a str,
+    pub lore: &'a LoreSections,
+    pub memories: &'a [String],
+    pub history: &'a [(bool, String)],
+}
+
+impl PromptTemplate {
+    pub fn builtin(name: &str) -> Option<PromptTemplate> {
+        let mut reply_prefix: Option<String> = None;
+        let (context, user_message, ai_message, stop_sequences): (String, &str, &str, Vec<&str>) = match name {
+            "pygmalion" => (
+                "{{system_prompt}}Text transcript of a conversation between {{user}} and {{char}}. {{roleplay}}\n{{user}}'s Persona: {{user_persona}}\n{{char}}'s Persona: {{persona}}\n{{scenario}}<START>{{example_dialogue}}\n<START>\n{{summary}}{{memories}}{{history}}".to_string(),
+                "{{user}}: {{message}}\n",
+                "{{char}}: {{message}}\n",
+                vec!["\n{{user}}:"],
+            ),
+            "llama2" => {
+                // the reply comes after the closing tag without [INST], like before templates existed
+                reply_prefix = Some("{{char}}: ".to_string());
+                (
+                    "<<SYS>>\n{{system_prompt}}You are {{char}}, {{persona}}\n{{scenario}}you are talking with {{user}}, {{user}} is {{user_persona}}\n{{roleplay}}\n[INST]\n{{example_dialogue}}\n[/INST]{{summary}}{{memories}}{{history}}<</SYS>>".to_string(),
+                    "[INST]{{user}}: {{message}}\n[/INST]\n",
+                    "[INST]{{char}}: {{message}}\n[/INST]\n",
+                    vec!["\n{{user}}:", "[INST]"],
+                )
+            },
+            "chatml" => (
+                format!("<|im_start|>system\n{}<|im_end|>\n{{{{history}}}}", SYSTEM),
+                "<|im_start|>user\n{{message}}<|im_end|>\n",
+                "<|im_start|>assistant\n{{message}}<|im_end|>\n",
+                vec!["<|im_end|>", "<|im_start|>"],
+            ),
+            "alpaca" => (
+                format!("{}\n{{{{history}}}}", SYSTEM),
+                "### Instruction:\n{{message}}\n\n",
+                "### Response:\n{{message}}\n\n",
+                vec!["### Instruction:", "\n{{user}}:"],
+            ),
+            "vicuna" => (
+                format!("{}\n{{{{history}}}}", SYSTEM),
+                "USER: {{message}}\n",
+                "ASSISTANT: {{message}}</s>\n",
+                vec!["\nUSER:", "</s>"],
+            ),
+            // <s> at the beginning is added by the tokenizer
+            "mistral" => (
+                format!("[INST] {}[/INST] Understood.</s>{{{{history}}}}", SYSTEM),
+                "[INST] {{message}} [/INST]",
+                "{{message}}</s>",
+                vec!["</s>", "[INST]"],
+            ),
+            "zephyr" => (
+                format!("<|system|>\n{}</s>\n{{{{history}}}}", SYSTEM),
+                "<|user|>\n{{message}}</s>\n",
+                "<|assistant|>\n{{message}}</s>\n",
+                vec!["</s>", "<|user|>"],
+            ),
+            _ => return None,
+        };
+        Some(PromptTemplate {
+            name: name.to_string(),
+            context,
+            user_message: user_message.to_string(),
+            ai_message: ai_message.to_string(),
+            stop_sequences: stop_sequences.iter().map(|s| s.to_string()).collect(),
+            reply_prefix,
+        })
+    }
+
+    pub fn custom(context: &str, user_message: &str, ai_message: &str, stop_sequences: Vec<String>) -> Result<PromptTemplate, String> {
+        if !context.contains("{{history}}") {
+            return Err("Custom prompt template context has to contain the {{history}} placeholder".to_string());
+        }
+        if !user_message.contains("{{message}}") || !ai_message.contains("{{message}}") {
+            return Err("Custom prompt template user and ai messages have to contain the {{message}} placeholder".to_string());
+        }
+        Ok(PromptTemplate {
+            name: "custom".to_string(),
+            context: context.to_string(),
+            user_message: user_message.to_string(),
+            ai_message: ai_message.to_string(),
+            stop_sequences,
+            reply_prefix: None,
+        })
+    }
+
+    pub fn render_message(&self, ai: bool, text: &str) -> String {
+        let message_template = if ai { &self.ai_message } else { &self.user_message };
+        message_template.replace("{{message}}", text)
+    }
+
+    fn reply_start(&self) -> &str {
+        match &self.reply_prefix {
+            Some(prefix) => prefix,
+            None => self.ai_message.split("{{message}}").next().unwrap_or(""),
+        }
+    }
+
+    // beginning of the ai message, the model continues generating from there
+    pub fn ai_prefix(&self) -> String {
+        self.reply_start().trim_end_matches(' ').to_string()
+    }
+
+    // put after the rendered prompt so that the model continues an unfinished ai message
+    pub fn unfinished_ai_message(&self, text: &str) -> String {
+        let prefix = self.reply_start();
+        format!("{}{}", &prefix[self.ai_prefix().len()..], text)
+    }
+
+    pub fn stop_sequences(&self, char_name: &str, user_name: &str) -> Vec<String> {
+        self.stop_sequences.iter()
+            .map(|s| s.replace("{{char}}", char_name).replace("{{user}}", user_name))
+            .filter(|s| !s.is_empty())
+            .collect()
+    }
+
+    // every placeholder is replaced in one pass, so placeholders inside of the persona
+    // or the messages are never expanded, only {{char}} and {{user}} are replaced in the sections
+    pub fn render(&self, sections: &PromptSections) -> String {
+        let names = |text: &str| fill(text, |name| match name {
+            "char" => Some(sections.char_name),
+            "user" => Some(sections.user_name),
+            _ => None,
+        });
+        let mut history: String = names(&sections.lore.before_history);
+        for (ai, text) in sections.history {
+            let message_template = if *ai { &self.ai_message } else { &self.user_message };
+            history += &fill(message_template, |name| match name {
+                "message" => Some(text.as_str()),
+                "char" => Some(sections.char_name),
+                "user" => Some(sections.user_name),
+                _ => None,
+            });
+        }
+        history += &names(&sections.lore.after_history);
+        if !sections.post_history_instructions.is_empty() {
+            history += &format!("{}\n", names(sections.post_history_instructions));
+        }
+        // {{original}} is the default system prompt of the frontend, there is none here
+        let system_prompt = if sections.system_prompt.is_empty() { String::new() } else { format!("{}\n", names(&sections.system_prompt.replace("{{original}}", ""))) };
+        let scenario = if sections.scenario.is_empty() { String::new() } else { format!("Scenario: {}\n", names(sections.scenario)) };
+        let mut persona = names(&(sections.lore.before_persona.clone() + sections.persona));
+        if !sections.lore.after_persona.is_empty() {
+            persona += &format!("\n{}", names(sections.lore.after_persona.trim_end()));
+        }
+        let summary = if sections.summary.is_empty() { String::new() } else { format!("Summary of the earlier conversation: {}\n", names(sections.summary)) };
+        let user_persona = names(sections.user_persona);
+        let example_dialogue = names(sections.example_dialogue);
+        let roleplay = names(sections.roleplay);
+        let memories = names(&sections.memories.concat());
+        fill(&(self.context.clone() + &self.ai_prefix()), |name| match name {
+            "system_prompt" => Some(system_prompt.as_str()),
+            "persona" => Some(persona.as_str()),
+            "scenario" => Some(scenario.as_str()),
+            "user_persona" => Some(user_persona.as_str()),
+            "example_dialogue" => Some(example_dialogue.as_str()),
+            "roleplay" => Some(roleplay.as_str()),
+            "summary" => Some(summary.as_str()),
+            "memories" => Some(memories.as_str()),
+            "history" => Some(history.as_str()),
+            "char" => Some(sections.char_name),
+            "user" => Some(sections.user_name),
+            _ => None,
+        })
+    }
+}
+
+// replaces {{name}} with the value for name, the text put in is not searched for placeholders again,
+// unknown placeholders are kept as they are
+fn fill<'a>(template: &str, value: impl Fn(&str) -> Option<&'a str>) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        result += &rest[..start];
+        let placeholder = rest[start + 2..].find("}}").and_then(|length| {
+            value(&rest[start + 2..start + 2 + length]).map(|text| (text, start + 4 + length))
+        });
+        match placeholder {
+            Some((text, end)) => {
+                result += text;
+                rest = &rest[end..];
+            },
+            None => {
+                result.push('{');
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    result += rest;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &PromptTemplate, persona: &str, user_persona: &str, memories: &[String], history: &[(bool, String)]) -> String {
+        template.render(&PromptSections {
+            char_name: "Aiko",
+            user_name: "Tom",
+            persona,
+            user_persona,
+            scenario: "",
+            system_prompt: "",
+            post_history_instructions: "",
+            example_dialogue: "{{user}}: Hi\n{{char}}: Hello",
+            roleplay: "",
+            summary: "",
+            lore: &LoreSections::default(),
+            memories,
+            history,
+        })
+    }
+
+    #[test]
+    fn placeholders_in_sections_are_not_expanded() {
+        let template = PromptTemplate::builtin("pygmalion").unwrap();
+        let memories = vec!["secret memory\n".to_string()];
+        let history = vec![(false, "say {{history}} and {{summary}}".to_string())];
+        let prompt = render(&template, "{{char}} repeats {{history}}, {{memories}} and {{summary}}", "{{user}} likes {{persona}}", &memories, &history);
+        assert!(prompt.contains("Aiko's Persona: Aiko repeats {{history}}, {{memories}} and {{summary}}\n"));
+        assert!(prompt.contains("Tom's Persona: Tom likes {{persona}}\n"));
+        assert!(prompt.contains("Tom: say {{history}} and {{summary}}\n"));
+        assert_eq!(prompt.matches("secret memory").count(), 1);
+    }
+
+    fn baseline_sections() -> (Vec<String>, Vec<(bool, String)>) {
+        (
+            vec!["{{user}}: do you like tea\n{{char}}: only green tea\n".to_string()],
+            vec![(true, "Hello Tom".to_string()), (false, "How are you?".to_string())],
+        )
+    }
+
+    // prompts built by 0.1.x before there were templates
+    #[test]
+    fn pygmalion_matches_the_old_prompt() {
+        let (memories, history) = baseline_sections();
+        let prompt = render(&PromptTemplate::builtin("pygmalion").unwrap(), "{{char}} is a librarian", "{{user}} is a student", &memories, &history);
+        let old_prompt = format!("Text transcript of a conversation between {} and {}. {}\n{}'s Persona: {}\n{}'s Persona: {}\n<START>{}\n<START>\n",
+            "Tom", "Aiko", "", "Tom", "Tom is a student", "Aiko", "Aiko is a librarian", "Tom: Hi\nAiko: Hello")
+            + "Tom: do you like tea\nAiko: only green tea\n"
+            + "Aiko: Hello Tom\nTom: How are you?\n"
+            + "Aiko:";
+        assert_eq!(prompt, old_prompt);
+    }
+
+    #[test]
+    fn llama2_matches_the_old_prompt() {
+        let (memories, history) = baseline_sections();
+        let prompt = render(&PromptTemplate::builtin("llama2").unwrap(), "{{char}} is a librarian", "{{user}} is a student", &memories, &history);
+        let old_prompt = format!("<<SYS>>\nYou are {}, {}\nyou are talking with {}, {} is {}\n{}\n[INST]\n{}\n[/INST]",
+            "Aiko", "Aiko is a librarian", "Tom", "Tom", "Tom is a student", "", "Tom: Hi\nAiko: Hello")
+            + "Tom: do you like tea\nAiko: only green tea\n"
+            + "[INST]Aiko: Hello Tom\n[/INST]\n[INST]Tom: How are you?\n[/INST]\n"
+            + "<</SYS>>"
+            + "Aiko:";
+        assert_eq!(prompt, old_prompt);
+    }
+
+    #[test]
+    fn every_builtin_template_ends_with_the_ai_prefix() {
+        for name in TEMPLATE_NAMES {
+            let template = PromptTemplate::builtin(name).unwrap();
+            let prompt = render(&template, "persona", "user persona", &[], &[(false, "Hi".to_string())]);
+            assert!(prompt.ends_with(&template.ai_prefix().replace("{{char}}", "Aiko")), "{}", name);
+            assert!(!prompt.contains("{{"), "{} leaves a placeholder: {}", name, prompt);
+        }
+        assert!(PromptTemplate::builtin("custom").is_none());
+    }
+
+    #[test]
+    fn unfinished_message_continues_after_the_ai_prefix() {
+        let pygmalion = PromptTemplate::builtin("pygmalion").unwrap();
+        assert_eq!(pygmalion.ai_prefix(), "{{char}}:");
+        assert_eq!(pygmalion.ai_prefix() + &pygmalion.unfinished_ai_message("Once upon"), "{{char}}: Once upon");
+        let llama2 = PromptTemplate::builtin("llama2").unwrap();
+        assert_eq!(llama2.ai_prefix(), "{{char}}:");
+        assert_eq!(llama2.unfinished_ai_message("Once upon"), " Once upon");
+        let chatml = PromptTemplate::builtin("chatml").unwrap();
+        assert_eq!(chatml.ai_prefix(), "<|im_start|>assistant\n");
+        assert_eq!(chatml.unfinished_ai_message("Once upon"), "Once upon");
+    }
+
+    #[test]
+    fn custom_template_needs_history_and_message_placeholders() {
+        let stop_sequences = vec!["\n{{user}}:".to_string()];
+        assert!(PromptTemplate::custom("{{persona}}", "{{user}}: {{message}}\n", "{{char}}: {{message}}\n", stop_sequences.clone()).is_err());
+        assert!(PromptTemplate::custom("{{persona}}\n{{history}}", "{{user}}: hi\n", "{{char}}: {{message}}\n", stop_sequences.clone()).is_err());
+        assert!(PromptTemplate::custom("{{persona}}\n{{history}}", "{{user}}: {{message}}\n", "{{char}}:\n", stop_sequences.clone()).is_err());
+        let template = PromptTemplate::custom("{{persona}}\n{{history}}", "> {{message}}\n", "< {{message}}\n", stop_sequences).unwrap();
+        assert_eq!(template.name, "custom");
+        assert_eq!(render(&template, "{{char}} is kind", "", &[], &[(false, "Hi".to_string()), (true, "Hello".to_string())]), "Aiko is kind\n> Hi\n< Hello\n<");
+        assert_eq!(template.stop_sequences("Aiko", "Tom"), vec!["\nTom:".to_string()]);
+    }
+}
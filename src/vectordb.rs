@@ -1,66 +1,801 @@
-use tantivy::collector::TopDocs;
-use tantivy::query::QueryParser;
+use pyo3::prelude::*;
+use tantivy::collector::{TopDocs, DocSetCollector};
+use tantivy::query::{QueryParser, AllQuery, BooleanQuery, BoostQuery, Occur, Query, RangeQuery, TermQuery};
 use tantivy::schema::*;
-use tantivy::Index;
+use tantivy::{Index, IndexReader, IndexWriter, ReloadPolicy, DocAddress, Searcher, Term};
+use std::ops::Bound;
 use tantivy::error::TantivyError;
+use tantivy::directory::error::LockError;
 use std::fs;
 use std::path::Path;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use crate::database::MemorySettings;
 
+// opened once per companion, the writer is opened on the first write and kept until the companion
+// is switched or dropped, entries are committed in batches and the reader is reloaded after every commit
 pub struct VectorDatabase {
     index: Index,
+    writer: Mutex<Option<BatchWriter>>,
+    reader: IndexReader,
+    // opstamp of the latest commit seen, other processes can commit to the same index
+    opstamp: AtomicU64,
+    next_id: AtomicU64,
+    id_field: Field,
     chat_field: Field,
+    embedding_field: Field,
+    timestamp_field: Field,
+    companion_id_field: Field,
+    chat_id_field: Field,
+    user_id_field: Field,
+    source_field: Field,
+    message_id_field: Field,
+    importance_field: Field,
+}
+
+struct BatchWriter {
+    writer: IndexWriter,
+    // entries added since the last commit, they can't be found until they are committed
+    pending: usize,
+    // when the first of them was added
+    since: Instant,
+}
+
+// entries written before metadata was stored only have the text, with the date inside of it
+#[derive(Clone, Default)]
+#[pyclass(get_all)]
+pub struct MemoryEntry {
+    pub id: u64,
+    pub text: String,
+    pub timestamp: Option<i64>,
+    pub companion_id: Option<u32>,
+    pub chat_id: Option<u32>,
+    pub user_id: Option<u32>,
+    pub source: String,
+    pub message_ids: Vec<u32>,
+    pub importance: f32,
+}
+
+#[derive(Clone, Default)]
+pub struct MemoryFilter {
+    pub source: Option<String>,
+    pub chat_id: Option<u32>,
+    pub user_id: Option<u32>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    // entries created from this message are left out
+    pub exclude_message_id: Option<u32>,
+}
+
+pub const MEMORY_SOURCES: [&str; 4] = ["conversation", "custom", "imported", "summary"];
+pub const MEMORY_SORTING: [&str; 3] = ["relevance", "newest", "oldest"];
+
+pub const RETRIEVAL_MODES: [&str; 3] = ["bm25", "vector", "hybrid"];
+pub const FUSION_METHODS: [&str; 2] = ["rrf", "weighted"];
+
+const WRITER_MEMORY: usize = 50_000_000;
+// how long to wait for another process that is writing to the index
+const WRITER_TIMEOUT: Duration = Duration::from_secs(5);
+// the latest messages are in short-term memory anyway, so entries don't have to be searchable right away
+const COMMIT_BATCH: usize = 8;
+const COMMIT_INTERVAL: Duration = Duration::from_secs(30);
+const CANDIDATES_PER_RESULT: usize = 4;
+// constant from the original reciprocal rank fusion paper, dampens the impact of the top ranks
+const RRF_K: f32 = 60.0;
+// bm25 score of a keyword match that counts as half relevant, scores are mapped to 0..1 by s / (s + k)
+// so that they don't depend on the other results of the search
+const BM25_HALF_SCORE: f32 = 4.0;
+
+fn build_schema() -> Schema {
+    let mut schema_builder = SchemaBuilder::default();
+    schema_builder.add_text_field("chat", TEXT | STORED);
+    schema_builder.add_bytes_field("embedding", STORED);
+    schema_builder.add_u64_field("id", INDEXED | STORED);
+    schema_builder.add_i64_field("timestamp", INDEXED | STORED | FAST);
+    schema_builder.add_u64_field("companion_id", INDEXED | STORED);
+    schema_builder.add_u64_field("chat_id", INDEXED | STORED);
+    // 0 for entries that are not tied to a user, sqlite ids start at 1
+    schema_builder.add_u64_field("user_id", INDEXED | STORED);
+    schema_builder.add_text_field("source", STRING | STORED);
+    schema_builder.add_u64_field("message_id", INDEXED | STORED);
+    schema_builder.add_f64_field("importance", STORED);
+    schema_builder.build()
 }
 
 impl VectorDatabase {
-    pub fn connect() -> tantivy::Result<Self> {
-        let mut schema_builder = SchemaBuilder::default();
-        let chat_field = schema_builder.add_text_field("chat", TEXT | STORED);
-        let schema = schema_builder.build();
-        if !Path::new("longterm_mem").exists() {
-            fs::create_dir("longterm_mem")?;
+    pub fn connect(path: &Path) -> tantivy::Result<Self> {
+        let schema = build_schema();
+        VectorDatabase::finish_upgrade(path)?;
+        if !path.exists() {
+            fs::create_dir_all(path)?;
         }
-        let companion_vector = match Index::open_in_dir("longterm_mem") {
+        let mut companion_vector = match Index::open_in_dir(path) {
             Ok(index) => index,
-            Err(_) => Index::create_in_dir("longterm_mem", schema)?,
+            Err(_) => Index::create_in_dir(path, schema.clone())?,
         };
+        let index_schema = companion_vector.schema();
+        if schema.fields().any(|(_, entry)| index_schema.get_field(entry.name()).is_err()) {
+            companion_vector = VectorDatabase::upgrade_index(path, companion_vector, schema)?;
+        }
+        let index_schema = companion_vector.schema();
+        let id_field = index_schema.get_field("id")?;
+        let reader = companion_vector.reader_builder().reload_policy(ReloadPolicy::Manual).try_into()?;
+        let next_id = VectorDatabase::max_id(&reader.searcher(), Some(id_field))? + 1;
         Ok(VectorDatabase {
+            writer: Mutex::new(None),
+            opstamp: AtomicU64::new(companion_vector.load_metas()?.opstamp),
+            reader,
+            next_id: AtomicU64::new(next_id),
+            id_field,
+            chat_field: index_schema.get_field("chat")?,
+            embedding_field: index_schema.get_field("embedding")?,
+            timestamp_field: index_schema.get_field("timestamp")?,
+            companion_id_field: index_schema.get_field("companion_id")?,
+            chat_id_field: index_schema.get_field("chat_id")?,
+            user_id_field: index_schema.get_field("user_id")?,
+            source_field: index_schema.get_field("source")?,
+            message_id_field: index_schema.get_field("message_id")?,
+            importance_field: index_schema.get_field("importance")?,
             index: companion_vector,
-            chat_field,
         })
     }
 
-    pub fn add_entry(&self, text: &str) -> Result<(), TantivyError> {
-        let mut writer = self.index.writer(50_000_000)?;
-        writer.add_document(tantivy::doc!(
-            self.chat_field => text
-        ))?;
-        writer.commit()?;
+    // indexes created by older versions are missing some of the fields,
+    // copy their documents to a new index with the current schema
+    fn upgrade_index(path: &Path, old_index: Index, schema: Schema) -> tantivy::Result<Index> {
+        let upgrade_path = path.with_extension("upgrade");
+        if upgrade_path.exists() {
+            fs::remove_dir_all(&upgrade_path)?;
+        }
+        fs::create_dir_all(&upgrade_path)?;
+        let new_index = Index::create_in_dir(&upgrade_path, schema.clone())?;
+        {
+            let old_schema = old_index.schema();
+            let reader = old_index.reader()?;
+            let searcher = reader.searcher();
+            let mut writer: IndexWriter = new_index.writer(WRITER_MEMORY)?;
+            let id_field = schema.get_field("id")?;
+            let user_id_field = schema.get_field("user_id")?;
+            let mut next_id = VectorDatabase::max_id(&searcher, old_schema.get_field("id").ok())? + 1;
+            // new ids follow the order in which the entries were added, tantivy doesn't keep the order of segments
+            // so they are sorted by the time their files were written (entries merged by 0.1.x only keep their order within the merged segment)
+            let written: Vec<Option<SystemTime>> = searcher.segment_readers().iter()
+                .map(|segment_reader| fs::metadata(path.join(format!("{}.store", segment_reader.segment_id().uuid_string()))).and_then(|metadata| metadata.modified()).ok())
+                .collect();
+            let mut doc_addresses: Vec<DocAddress> = searcher.search(&AllQuery, &DocSetCollector)?.into_iter().collect();
+            doc_addresses.sort_by_key(|doc_address| (written[doc_address.segment_ord as usize], doc_address.segment_ord, doc_address.doc_id));
+            for doc_address in doc_addresses {
+                let old_doc = searcher.doc(doc_address)?;
+                let mut new_doc = Document::default();
+                for field_value in old_doc.field_values() {
+                    if let Ok(field) = schema.get_field(old_schema.get_field_name(field_value.field())) {
+                        new_doc.add_field_value(field, field_value.value().clone());
+                    }
+                }
+                // entries from before ids existed get one
+                if new_doc.get_first(id_field).is_none() {
+                    new_doc.add_u64(id_field, next_id);
+                    next_id += 1;
+                }
+                // entries from before users were stored are shared by all of them
+                if new_doc.get_first(user_id_field).is_none() {
+                    new_doc.add_u64(user_id_field, 0);
+                }
+                writer.add_document(new_doc)?;
+            }
+            writer.commit()?;
+        }
+        drop(new_index);
+        drop(old_index);
+        // the old index is kept until the new one is in place, finish_upgrade cleans up after a crash
+        let old_path = path.with_extension("old");
+        if old_path.exists() {
+            fs::remove_dir_all(&old_path)?;
+        }
+        fs::rename(path, &old_path)?;
+        fs::rename(&upgrade_path, path)?;
+        fs::remove_dir_all(&old_path)?;
+        Index::open_in_dir(path)
+    }
+
+    // an upgrade interrupted after the old index was moved aside, puts it back
+    // if the new one didn't replace it yet, the upgrade is then done again
+    fn finish_upgrade(path: &Path) -> std::io::Result<()> {
+        let old_path = path.with_extension("old");
+        if !old_path.exists() {
+            return Ok(());
+        }
+        if path.join("meta.json").exists() {
+            fs::remove_dir_all(&old_path)
+        } else {
+            if path.exists() {
+                fs::remove_dir_all(path)?;
+            }
+            fs::rename(&old_path, path)
+        }
+    }
+
+    // long-term memory used to be a single index directly in the memory directory,
+    // move it to the directory of the companion it belonged to
+    pub fn migrate_single_index(path: &Path, companion_path: &Path) -> std::io::Result<()> {
+        if !path.join("meta.json").exists() || companion_path.exists() {
+            return Ok(());
+        }
+        fs::create_dir_all(companion_path)?;
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                fs::rename(entry.path(), companion_path.join(entry.file_name()))?;
+            }
+        }
         Ok(())
     }
 
-    pub fn get_matches(&self, query_string: &str, limit: usize) -> Result<Vec<String>, TantivyError> {
-        let sanitized_query = query_string.chars().filter(|c| c.is_alphanumeric() || c.is_whitespace()).collect::<String>().to_lowercase();
-        let reader = self.index.reader()?;
-        let searcher = reader.searcher();
-        let qp = QueryParser::for_index(&self.index, vec![self.chat_field]);
-        let query = qp.parse_query(&sanitized_query)?;
+    fn max_id(searcher: &Searcher, id_field: Option<Field>) -> Result<u64, TantivyError> {
+        let mut max_id = 0;
+        if let Some(id_field) = id_field {
+            for doc_address in searcher.search(&AllQuery, &DocSetCollector)? {
+                if let Some(id) = searcher.doc(doc_address)?.get_first(id_field).and_then(|val| val.as_u64()) {
+                    max_id = max_id.max(id);
+                }
+            }
+        }
+        Ok(max_id)
+    }
+
+    fn entry_to_doc(&self, entry: &MemoryEntry, embedding: Option<&[f32]>) -> Document {
+        let mut doc = tantivy::doc!(
+            self.id_field => entry.id,
+            self.chat_field => entry.text.as_str(),
+            self.source_field => entry.source.as_str(),
+            self.importance_field => entry.importance as f64,
+        );
+        if let Some(timestamp) = entry.timestamp {
+            doc.add_i64(self.timestamp_field, timestamp);
+        }
+        if let Some(companion_id) = entry.companion_id {
+            doc.add_u64(self.companion_id_field, companion_id as u64);
+        }
+        if let Some(chat_id) = entry.chat_id {
+            doc.add_u64(self.chat_id_field, chat_id as u64);
+        }
+        doc.add_u64(self.user_id_field, entry.user_id.unwrap_or(0) as u64);
+        for message_id in &entry.message_ids {
+            doc.add_u64(self.message_id_field, *message_id as u64);
+        }
+        if let Some(embedding) = embedding {
+            doc.add_bytes(self.embedding_field, embedding_to_bytes(embedding));
+        }
+        doc
+    }
+
+    // the writer is opened on first use, waiting while another process holds the lock of the index,
+    // entries committed by it are loaded first so that ids keep increasing
+    fn writer(&self) -> Result<MutexGuard<'_, Option<BatchWriter>>, TantivyError> {
+        let mut guard = self.writer.lock().map_err(|_| TantivyError::Poisoned)?;
+        if guard.is_some() {
+            return Ok(guard);
+        }
+        let start = Instant::now();
+        let writer = loop {
+            match self.index.writer(WRITER_MEMORY) {
+                Ok(writer) => break writer,
+                Err(TantivyError::LockFailure(LockError::LockBusy, _)) if start.elapsed() < WRITER_TIMEOUT => {
+                    std::thread::sleep(Duration::from_millis(50));
+                },
+                Err(TantivyError::LockFailure(LockError::LockBusy, _)) => {
+                    return Err(TantivyError::SystemError(format!("long-term memory is being written by another process for more than {} seconds", WRITER_TIMEOUT.as_secs())));
+                },
+                Err(e) => return Err(e),
+            }
+        };
+        let opstamp = self.index.load_metas()?.opstamp;
+        if opstamp != self.opstamp.load(Ordering::SeqCst) {
+            self.reader.reload()?;
+            let next_id = VectorDatabase::max_id(&self.reader.searcher(), Some(self.id_field))? + 1;
+            self.next_id.fetch_max(next_id, Ordering::SeqCst);
+            self.opstamp.store(opstamp, Ordering::SeqCst);
+        }
+        *guard = Some(BatchWriter { writer, pending: 0, since: Instant::now() });
+        Ok(guard)
+    }
+
+    fn with_writer<T>(&self, write: impl FnOnce(&mut BatchWriter) -> Result<T, TantivyError>) -> Result<T, TantivyError> {
+        let mut guard = self.writer()?;
+        match guard.as_mut() {
+            Some(batch) => write(batch),
+            None => Err(TantivyError::SystemError("long-term memory writer is not open".to_string())),
+        }
+    }
+
+    // merges started by the commit run in the background
+    fn commit(&self, batch: &mut BatchWriter) -> Result<(), TantivyError> {
+        let opstamp = batch.writer.commit()?;
+        batch.pending = 0;
+        self.opstamp.store(opstamp, Ordering::SeqCst);
+        self.reader.reload()
+    }
+
+    // commits entries that were not committed yet, all_pending false commits them only if they waited too long
+    fn commit_pending(&self, all_pending: bool) -> Result<(), TantivyError> {
+        let mut guard = self.writer.lock().map_err(|_| TantivyError::Poisoned)?;
+        if let Some(batch) = guard.as_mut() {
+            if batch.pending > 0 && (all_pending || batch.since.elapsed() >= COMMIT_INTERVAL) {
+                self.commit(batch)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<(), TantivyError> {
+        self.commit_pending(true)
+    }
+
+    // reloads the reader if another process committed to the index
+    fn searcher(&self) -> Result<Searcher, TantivyError> {
+        let opstamp = self.index.load_metas()?.opstamp;
+        if opstamp != self.opstamp.load(Ordering::SeqCst) {
+            self.reader.reload()?;
+            self.opstamp.store(opstamp, Ordering::SeqCst);
+        }
+        Ok(self.reader.searcher())
+    }
+
+    // id of the entry is assigned here, the one in entry is ignored
+    pub fn add_entry(&self, entry: &MemoryEntry, embedding: Option<&[f32]>) -> Result<u64, TantivyError> {
+        self.with_writer(|batch| {
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            batch.writer.add_document(self.entry_to_doc(&MemoryEntry { id, ..entry.clone() }, embedding))?;
+            if batch.pending == 0 {
+                batch.since = Instant::now();
+            }
+            batch.pending += 1;
+            if batch.pending >= COMMIT_BATCH || batch.since.elapsed() >= COMMIT_INTERVAL {
+                self.commit(batch)?;
+            }
+            Ok(id)
+        })
+    }
+
+    // adds all entries in one commit, creating a segment for every entry makes the index slow
+    pub fn add_entries(&self, entries: &[(MemoryEntry, Option<Vec<f32>>)]) -> Result<Vec<u64>, TantivyError> {
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.with_writer(|batch| {
+            let mut ids: Vec<u64> = Vec::new();
+            for (entry, embedding) in entries {
+                let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                batch.writer.add_document(self.entry_to_doc(&MemoryEntry { id, ..entry.clone() }, embedding.as_deref()))?;
+                ids.push(id);
+            }
+            self.commit(batch)?;
+            Ok(ids)
+        })
+    }
+
+    fn find_entry(&self, searcher: &Searcher, id: u64) -> Result<Option<Document>, TantivyError> {
+        let query = TermQuery::new(Term::from_field_u64(self.id_field, id), IndexRecordOption::Basic);
+        match searcher.search(&query, &TopDocs::with_limit(1))?.first() {
+            Some((_, doc_address)) => Ok(Some(searcher.doc(*doc_address)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_entry(&self, id: u64) -> Result<Option<MemoryEntry>, TantivyError> {
+        self.flush()?;
+        Ok(self.find_entry(&self.searcher()?, id)?.map(|doc| self.entry_from_doc(&doc)))
+    }
+
+    // entries ordered by id (oldest first)
+    pub fn list_entries(&self, offset: usize, limit: usize) -> Result<Vec<MemoryEntry>, TantivyError> {
+        self.flush()?;
+        let searcher = self.searcher()?;
+        let mut entries: Vec<MemoryEntry> = Vec::new();
+        for doc_address in searcher.search(&AllQuery, &DocSetCollector)? {
+            entries.push(self.entry_from_doc(&searcher.doc(doc_address)?));
+        }
+        entries.sort_by_key(|entry| entry.id);
+        Ok(entries.into_iter().skip(offset).take(limit).collect())
+    }
+
+    pub fn count_entries(&self) -> Result<u64, TantivyError> {
+        self.flush()?;
+        Ok(self.searcher()?.num_docs())
+    }
+
+    // keeps id and metadata of the entry, returns false if there is no entry with the id
+    pub fn edit_entry(&self, id: u64, text: &str, embedding: Option<&[f32]>) -> Result<bool, TantivyError> {
+        self.with_writer(|batch| {
+            if batch.pending > 0 {
+                self.commit(batch)?;
+            }
+            let mut entry = match self.find_entry(&self.reader.searcher(), id)? {
+                Some(doc) => self.entry_from_doc(&doc),
+                None => return Ok(false),
+            };
+            entry.text = text.to_string();
+            batch.writer.delete_term(Term::from_field_u64(self.id_field, id));
+            batch.writer.add_document(self.entry_to_doc(&entry, embedding))?;
+            self.commit(batch)?;
+            Ok(true)
+        })
+    }
+
+    // returns false if there is no entry with the id
+    pub fn delete_entry(&self, id: u64) -> Result<bool, TantivyError> {
+        self.with_writer(|batch| {
+            if batch.pending > 0 {
+                self.commit(batch)?;
+            }
+            if self.find_entry(&self.reader.searcher(), id)?.is_none() {
+                return Ok(false);
+            }
+            batch.writer.delete_term(Term::from_field_u64(self.id_field, id));
+            self.commit(batch)?;
+            Ok(true)
+        })
+    }
+
+    // removes every entry that was created from the message, including entries that are not committed yet
+    pub fn delete_message_entries(&self, message_id: u32) -> Result<(), TantivyError> {
+        self.with_writer(|batch| {
+            batch.writer.delete_term(Term::from_field_u64(self.message_id_field, message_id as u64));
+            self.commit(batch)
+        })
+    }
+
+    // merges all segments into one and removes files of deleted segments
+    pub fn optimize(&self) -> Result<(), TantivyError> {
+        self.with_writer(|batch| {
+            self.commit(batch)?;
+            let segment_ids = self.index.searchable_segment_ids()?;
+            if segment_ids.len() > 1 {
+                batch.writer.merge(&segment_ids).wait()?;
+            }
+            batch.writer.garbage_collect_files().wait()?;
+            self.reader.reload()
+        })
+    }
+
+    pub fn get_matches(&self, query_string: &str, query_embedding: Option<&[f32]>, limit: usize, settings: &MemorySettings, filter: &MemoryFilter, sort_by: &str) -> Result<Vec<(f32, MemoryEntry)>, TantivyError> {
         if limit == 0 {
             return Ok(Vec::new());
         }
-        let matches: Vec<(f32, tantivy::DocAddress)> = searcher.search(&query, &TopDocs::with_limit(limit))?;
-        let mut result: Vec<String> = Vec::new();
-        for (_, text_addr) in matches {
-            let retrieved = searcher.doc(text_addr)?;
-            let r = retrieved.get_first(self.chat_field).and_then(|val| val.as_text()).unwrap_or("");
-            result.push(r.to_string());
+        self.commit_pending(false)?;
+        let searcher = self.searcher()?;
+        // fetch more candidates than needed, duplicates are removed and fusion reorders them
+        let candidates = limit * CANDIDATES_PER_RESULT;
+        // scores are between 0 and 1 in every mode, so min_score doesn't depend on it
+        let matches: Vec<(f32, DocAddress)> = match (settings.retrieval.as_str(), query_embedding) {
+            ("vector", Some(embedding)) => self.vector_search(&searcher, embedding, candidates, filter)?
+                .into_iter().map(|(score, doc_address)| (score.max(0.0), doc_address)).collect(),
+            ("hybrid", Some(embedding)) => {
+                let keyword_matches = self.bm25_search(&searcher, query_string, candidates, filter)?;
+                let vector_matches = self.vector_search(&searcher, embedding, candidates, filter)?;
+                fuse(&keyword_matches, &vector_matches, settings)
+            },
+            // without an embedding of the query only keyword search is possible
+            _ => self.bm25_search(&searcher, query_string, candidates, filter)?
+                .into_iter().map(|(score, doc_address)| (bm25_relevance(score), doc_address)).collect(),
+        };
+        let mut result: Vec<(f32, MemoryEntry)> = Vec::new();
+        for (score, text_addr) in matches.into_iter().filter(|(score, _)| *score >= settings.min_score) {
+            let entry = self.entry_from_doc(&searcher.doc(text_addr)?);
+            if !result.iter().any(|(_, existing)| existing.text == entry.text) {
+                result.push((score, entry));
+            }
+            if result.len() == limit {
+                break;
+            }
+        }
+        match sort_by {
+            "newest" => result.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.timestamp)),
+            "oldest" => result.sort_by_key(|(_, entry)| entry.timestamp),
+            _ => {},
         }
         Ok(result)
     }
 
+    fn entry_from_doc(&self, doc: &Document) -> MemoryEntry {
+        MemoryEntry {
+            id: doc.get_first(self.id_field).and_then(|val| val.as_u64()).unwrap_or(0),
+            text: doc.get_first(self.chat_field).and_then(|val| val.as_text()).unwrap_or("").to_string(),
+            timestamp: doc.get_first(self.timestamp_field).and_then(|val| val.as_i64()),
+            companion_id: doc.get_first(self.companion_id_field).and_then(|val| val.as_u64()).map(|id| id as u32),
+            chat_id: doc.get_first(self.chat_id_field).and_then(|val| val.as_u64()).map(|id| id as u32),
+            user_id: doc.get_first(self.user_id_field).and_then(|val| val.as_u64()).filter(|id| *id != 0).map(|id| id as u32),
+            source: doc.get_first(self.source_field).and_then(|val| val.as_text()).unwrap_or("conversation").to_string(),
+            message_ids: doc.get_all(self.message_id_field).filter_map(|val| val.as_u64()).map(|id| id as u32).collect(),
+            importance: doc.get_first(self.importance_field).and_then(|val| val.as_f64()).unwrap_or(1.0) as f32,
+        }
+    }
+
+    // restricts a search to entries matching the filter, None if nothing is filtered
+    fn filter_query(&self, filter: &MemoryFilter) -> Option<Box<dyn Query>> {
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+        if let Some(source) = &filter.source {
+            clauses.push((Occur::Must, Box::new(TermQuery::new(Term::from_field_text(self.source_field, source), IndexRecordOption::Basic))));
+        }
+        if let Some(chat_id) = filter.chat_id {
+            clauses.push((Occur::Must, Box::new(TermQuery::new(Term::from_field_u64(self.chat_id_field, chat_id as u64), IndexRecordOption::Basic))));
+        }
+        // entries of the user and entries shared by all users
+        if let Some(user_id) = filter.user_id {
+            let user_clauses: Vec<(Occur, Box<dyn Query>)> = [user_id as u64, 0].iter()
+                .map(|id| (Occur::Should, Box::new(TermQuery::new(Term::from_field_u64(self.user_id_field, *id), IndexRecordOption::Basic)) as Box<dyn Query>))
+                .collect();
+            clauses.push((Occur::Must, Box::new(BooleanQuery::new(user_clauses))));
+        }
+        if filter.since.is_some() || filter.until.is_some() {
+            let lower = filter.since.map_or(Bound::Unbounded, Bound::Included);
+            let upper = filter.until.map_or(Bound::Unbounded, Bound::Included);
+            clauses.push((Occur::Must, Box::new(RangeQuery::new_i64_bounds("timestamp".to_string(), lower, upper))));
+        }
+        if let Some(message_id) = filter.exclude_message_id {
+            // a query with only excluding clauses matches nothing
+            if clauses.is_empty() {
+                clauses.push((Occur::Must, Box::new(AllQuery)));
+            }
+            clauses.push((Occur::MustNot, Box::new(TermQuery::new(Term::from_field_u64(self.message_id_field, message_id as u64), IndexRecordOption::Basic))));
+        }
+        if clauses.is_empty() {
+            None
+        } else {
+            Some(Box::new(BooleanQuery::new(clauses)))
+        }
+    }
+
+    fn bm25_search(&self, searcher: &Searcher, query_string: &str, limit: usize, filter: &MemoryFilter) -> Result<Vec<(f32, DocAddress)>, TantivyError> {
+        let sanitized_query = query_string.chars().filter(|c| c.is_alphanumeric() || c.is_whitespace()).collect::<String>().to_lowercase();
+        let qp = QueryParser::for_index(&self.index, vec![self.chat_field]);
+        let query = qp.parse_query(&sanitized_query)?;
+        match self.filter_query(filter) {
+            // filter clauses are boosted to zero so that only the text query scores
+            Some(filter_query) => searcher.search(&BooleanQuery::new(vec![(Occur::Must, query), (Occur::Must, Box::new(BoostQuery::new(filter_query, 0.0)))]), &TopDocs::with_limit(limit)),
+            None => searcher.search(&query, &TopDocs::with_limit(limit)),
+        }
+    }
+
+    // brute force cosine similarity over every entry that has an embedding of the same size
+    fn vector_search(&self, searcher: &Searcher, query_embedding: &[f32], limit: usize, filter: &MemoryFilter) -> Result<Vec<(f32, DocAddress)>, TantivyError> {
+        let mut matches: Vec<(f32, DocAddress)> = Vec::new();
+        let candidates = match self.filter_query(filter) {
+            Some(filter_query) => searcher.search(&filter_query, &DocSetCollector)?,
+            None => searcher.search(&AllQuery, &DocSetCollector)?,
+        };
+        for doc_address in candidates {
+            let doc = searcher.doc(doc_address)?;
+            if let Some(bytes) = doc.get_first(self.embedding_field).and_then(|val| val.as_bytes()) {
+                let embedding = embedding_from_bytes(bytes);
+                if embedding.len() == query_embedding.len() {
+                    matches.push((cosine_similarity(query_embedding, &embedding), doc_address));
+                }
+            }
+        }
+        matches.sort_by(|a, b| b.0.total_cmp(&a.0));
+        matches.truncate(limit);
+        Ok(matches)
+    }
+
     pub fn erase_memory(&self) -> Result<(), TantivyError> {
-        let mut writer = self.index.writer(50_000_000)?;
-        writer.delete_all_documents()?;
-        writer.commit()?;
-        Ok(())
+        self.with_writer(|batch| {
+            batch.writer.delete_all_documents()?;
+            self.commit(batch)
+        })
+    }
+}
+
+// entries that were not committed yet would be lost with the writer
+impl Drop for VectorDatabase {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("Error while saving long-term memory: {}", e);
+        }
+    }
+}
+
+fn bm25_relevance(score: f32) -> f32 {
+    let score = score.max(0.0);
+    score / (score + BM25_HALF_SCORE)
+}
+
+// the score of a result is the weighted mean of its keyword relevance and cosine similarity,
+// reciprocal rank fusion only decides the order, so min_score means the same with both fusions
+fn fuse(keyword_matches: &[(f32, DocAddress)], vector_matches: &[(f32, DocAddress)], settings: &MemorySettings) -> Vec<(f32, DocAddress)> {
+    // (score, reciprocal rank fusion score)
+    let mut combined: HashMap<DocAddress, (f32, f32)> = HashMap::new();
+    let total_weight = (settings.keyword_weight + settings.vector_weight).max(f32::EPSILON);
+    for (rank, (score, doc_address)) in keyword_matches.iter().enumerate() {
+        let scores = combined.entry(*doc_address).or_insert((0.0, 0.0));
+        scores.0 += settings.keyword_weight * bm25_relevance(*score) / total_weight;
+        scores.1 += settings.keyword_weight / (RRF_K + rank as f32 + 1.0);
+    }
+    for (rank, (score, doc_address)) in vector_matches.iter().enumerate() {
+        let scores = combined.entry(*doc_address).or_insert((0.0, 0.0));
+        scores.0 += settings.vector_weight * score.max(0.0) / total_weight;
+        scores.1 += settings.vector_weight / (RRF_K + rank as f32 + 1.0);
+    }
+    let mut fused: Vec<(f32, f32, DocAddress)> = combined.into_iter().map(|(doc_address, (score, rrf_score))| (score, rrf_score, doc_address)).collect();
+    if settings.fusion == "weighted" {
+        fused.sort_by(|a, b| b.0.total_cmp(&a.0));
+    } else {
+        fused.sort_by(|a, b| b.1.total_cmp(&a.1));
+    }
+    fused.into_iter().map(|(score, _, doc_address)| (score, doc_address)).collect()
+}
+
+fn embedding_to_bytes(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+fn embedding_from_bytes(bytes: &[u8]) -> Vec<f32> {
+    bytes.chunks_exact(4).map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])).collect()
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // removed with everything in it when the test ends
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("ai-companion-test-{}-{}", name, std::process::id()));
+            if path.exists() {
+                fs::remove_dir_all(&path).unwrap();
+            }
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn entries(texts: &[&str]) -> Vec<(MemoryEntry, Option<Vec<f32>>)> {
+        texts.iter()
+            .map(|text| (MemoryEntry { text: text.to_string(), source: "conversation".to_string(), importance: 1.0, ..Default::default() }, None))
+            .collect()
+    }
+
+    // index of 0.1.x, only the text of the entries was stored
+    fn legacy_index(path: &Path, texts: &[&str]) {
+        let mut schema_builder = Schema::builder();
+        let chat_field = schema_builder.add_text_field("chat", TEXT | STORED);
+        let index = Index::create_in_dir(path, schema_builder.build()).unwrap();
+        let mut writer: IndexWriter = index.writer(WRITER_MEMORY).unwrap();
+        // one commit per entry, so the entries are spread over several segments
+        for text in texts {
+            let mut doc = Document::default();
+            doc.add_text(chat_field, text);
+            writer.add_document(doc).unwrap();
+            writer.commit().unwrap();
+        }
+    }
+
+    const LEGACY_TEXTS: [&str; 4] = ["first memory", "second memory", "third memory", "fourth memory"];
+
+    fn assert_upgraded(memory: &VectorDatabase) {
+        let upgraded = memory.list_entries(0, 10).unwrap();
+        assert_eq!(upgraded.iter().map(|entry| entry.id).collect::<Vec<u64>>(), vec![1, 2, 3, 4]);
+        assert_eq!(upgraded.iter().map(|entry| entry.text.as_str()).collect::<Vec<&str>>(), LEGACY_TEXTS);
+        // shared by all users
+        assert!(upgraded.iter().all(|entry| entry.user_id.is_none()));
+    }
+
+    #[test]
+    fn legacy_index_is_upgraded_in_order() {
+        let dir = TempDir::new("upgrade");
+        let path = dir.0.join("companion_1");
+        fs::create_dir_all(&path).unwrap();
+        legacy_index(&path, &LEGACY_TEXTS);
+        {
+            let memory = VectorDatabase::connect(&path).unwrap();
+            assert_upgraded(&memory);
+            memory.add_entries(&entries(&["fifth memory"])).unwrap();
+        }
+        assert!(!path.with_extension("old").exists());
+        assert!(!path.with_extension("upgrade").exists());
+        // connecting to the upgraded index keeps the ids
+        let memory = VectorDatabase::connect(&path).unwrap();
+        assert_eq!(memory.list_entries(0, 10).unwrap().iter().map(|entry| entry.id).collect::<Vec<u64>>(), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn interrupted_upgrade_is_done_again() {
+        let dir = TempDir::new("interrupted-upgrade");
+        let path = dir.0.join("companion_1");
+        let old_path = path.with_extension("old");
+        fs::create_dir_all(&old_path).unwrap();
+        legacy_index(&old_path, &LEGACY_TEXTS);
+        // the old index was moved aside, but the new one didn't replace it
+        fs::create_dir_all(&path).unwrap();
+        let memory = VectorDatabase::connect(&path).unwrap();
+        assert_upgraded(&memory);
+        assert!(!old_path.exists());
+    }
+
+    #[test]
+    fn single_index_is_moved_to_the_companion() {
+        let dir = TempDir::new("single-index");
+        legacy_index(&dir.0, &LEGACY_TEXTS);
+        let companion_path = dir.0.join("companion_1");
+        VectorDatabase::migrate_single_index(&dir.0, &companion_path).unwrap();
+        assert!(!dir.0.join("meta.json").exists());
+        let memory = VectorDatabase::connect(&companion_path).unwrap();
+        assert_upgraded(&memory);
+    }
+
+    #[test]
+    fn weak_best_match_is_filtered() {
+        let dir = TempDir::new("min-score");
+        let memory = VectorDatabase::connect(&dir.0).unwrap();
+        memory.add_entries(&entries(&[
+            "{{user}}: what is your favourite colour\n{{char}}: blue, i told you that on the first day",
+            "{{user}}: we went hiking that day\n{{char}}: the mountains were beautiful",
+            "{{user}}: i baked bread all day\n{{char}}: it smelled wonderful",
+            "{{user}}: the train was late again that day\n{{char}}: you should leave earlier",
+            "{{user}}: it rained the whole day\n{{char}}: good weather for reading",
+            "{{user}}: my sister visited for a day\n{{char}}: did you have fun together",
+        ])).unwrap();
+        let settings = MemorySettings { min_score: 0.3, ..Default::default() };
+        let relevant = memory.get_matches("favourite colour", None, 3, &settings, &MemoryFilter::default(), "relevance").unwrap();
+        assert_eq!(relevant.len(), 1);
+        assert!(relevant[0].1.text.contains("blue"));
+        // every entry mentions a day, the best of them is still not relevant
+        let unfiltered = memory.get_matches("day", None, 3, &MemorySettings::default(), &MemoryFilter::default(), "relevance").unwrap();
+        assert!(!unfiltered.is_empty());
+        assert!(unfiltered.iter().all(|(score, _)| *score < settings.min_score));
+        assert!(memory.get_matches("day", None, 3, &settings, &MemoryFilter::default(), "relevance").unwrap().is_empty());
+    }
+
+    #[test]
+    fn fusion_changes_order_but_not_scores() {
+        let first = DocAddress::new(0, 0);
+        let second = DocAddress::new(0, 1);
+        let keyword_matches = [(12.0, first), (2.0, second)];
+        let vector_matches = [(0.9, second), (-0.2, first)];
+        let rrf = fuse(&keyword_matches, &vector_matches, &MemorySettings { fusion: "rrf".to_string(), ..Default::default() });
+        let weighted = fuse(&keyword_matches, &vector_matches, &MemorySettings { fusion: "weighted".to_string(), ..Default::default() });
+        for (score, doc_address) in &rrf {
+            assert!((0.0..=1.0).contains(score));
+            assert_eq!(weighted.iter().find(|(_, other)| other == doc_address).map(|(score, _)| *score), Some(*score));
+        }
+        assert_eq!(weighted[0].1, second);
+    }
+
+    #[test]
+    fn entries_of_excluded_message_are_not_matched() {
+        let dir = TempDir::new("exclude-message");
+        let memory = VectorDatabase::connect(&dir.0).unwrap();
+        let mut replies = entries(&[
+            "{{user}}: where did we meet\n{{char}}: in the old library",
+            "{{user}}: where did we meet\n{{char}}: at the train station",
+        ]);
+        replies[0].0.message_ids = vec![1, 2];
+        replies[1].0.message_ids = vec![3, 4];
+        memory.add_entries(&replies).unwrap();
+        let filter = MemoryFilter { exclude_message_id: Some(2), ..Default::default() };
+        let matches = memory.get_matches("where did we meet", None, 3, &MemorySettings::default(), &filter, "relevance").unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].1.message_ids, vec![3, 4]);
     }
 }